@@ -13,19 +13,97 @@ const MAX_BODY_LENGTH: u32 = 32 * 1024 * 1024;
 pub struct Blob {
     pub data_type: BlobType,
     pub data: Vec<u8>,
+    /// How the blob was stored in the file it was parsed from, or `None` for blobs built in memory
+    pub layout: Option<BlobLayout>,
+}
+
+/// The sizes and compression of a blob as stored in a file.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BlobLayout {
+    /// The length of the serialized blob header, not counting its 4 byte length prefix
+    pub header_size: u32,
+    /// The length of the serialized blob body, including the compressed data
+    pub data_size: u32,
+    pub compression: BlobCompression,
+}
+
+impl BlobLayout {
+    /// The number of bytes the blob takes up in the file, including the length prefix.
+    pub fn stored_size(&self) -> u64 {
+        4 + self.header_size as u64 + self.data_size as u64
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
+pub enum BlobCompression {
+    Raw,
+    Zlib,
+    Lzma,
+    Bzip2,
+    /// The blob body holds no data in any known format
+    Unknown,
+}
+
+impl BlobCompression {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            BlobCompression::Raw => "raw",
+            BlobCompression::Zlib => "zlib",
+            BlobCompression::Lzma => "lzma",
+            BlobCompression::Bzip2 => "bzip2",
+            BlobCompression::Unknown => "unknown",
+        }
+    }
+
+    fn of(blob: &file::Blob) -> BlobCompression {
+        if blob.has_raw() {
+            BlobCompression::Raw
+        } else if blob.has_zlib_data() {
+            BlobCompression::Zlib
+        } else if blob.has_lzma_data() {
+            BlobCompression::Lzma
+        } else if blob.has_OBSOLETE_bzip2_data() {
+            BlobCompression::Bzip2
+        } else {
+            BlobCompression::Unknown
+        }
+    }
 }
 
 impl Blob {
     pub fn parse(reader: &mut Read) -> Result<Blob, PbfParseError> {
-        let header = parse_header(reader)?;
+        let (header, header_size) = parse_header(reader)?;
         let blob = parse_blob(reader, &header)?;
         let data_type = BlobType::try_from(header.get_field_type())?;
+        let layout = BlobLayout {
+            header_size,
+            data_size: header.get_datasize() as u32,
+            compression: BlobCompression::of(&blob),
+        };
         let data = parse_data(blob)?;
-        Ok(Blob { data_type, data })
+        Ok(Blob { data_type, data, layout: Some(layout) })
+    }
+
+    /// Checks whether a well-formed blob header starts at the current position of the reader, and that
+    /// the blob it describes fits within the `remaining` bytes. The blob body itself is not read.
+    pub fn probe(reader: &mut Read, remaining: u64) -> bool {
+        use byteorder::{BigEndian, ReadBytesExt};
+        let header_length = match reader.read_u32::<BigEndian>() {
+            Ok(length) if length < MAX_HEADER_LENGTH => length,
+            _ => return false,
+        };
+        let header: file::BlobHeader = match ::read_message(reader, header_length as usize) {
+            Ok(header) => header,
+            Err(_) => return false,
+        };
+        let data_length = header.get_datasize();
+        BlobType::try_from(header.get_field_type()).is_ok()
+            && data_length >= 0 && data_length as u32 <= MAX_BODY_LENGTH
+            && 4 + header_length as u64 + data_length as u64 <= remaining
     }
 
     pub fn new(data_type: BlobType, data: Vec<u8>) -> Blob {
-        Blob { data_type, data }
+        Blob { data_type, data, layout: None }
     }
 
     pub fn write(&self, writer: &mut Write) -> Result<(), PbfParseError> {
@@ -52,13 +130,13 @@ fn write_header(writer: &mut Write, data_type: &BlobType, data_len: usize) -> Re
     Ok(())
 }
 
-fn parse_header(reader: &mut Read) -> Result<file::BlobHeader, PbfParseError> {
+fn parse_header(reader: &mut Read) -> Result<(file::BlobHeader, u32), PbfParseError> {
     use byteorder::{BigEndian, ReadBytesExt};
     let header_length = reader.read_u32::<BigEndian>()?;
     if header_length >= MAX_HEADER_LENGTH {
         return Err(PbfParseError::InvalidHeaderLength(header_length));
     }
-    ::read_message(reader, header_length as usize)
+    Ok((::read_message(reader, header_length as usize)?, header_length))
 }
 
 fn write_blob(writer: &mut Write, mut data: Vec<u8>) -> Result<(), PbfParseError> {
@@ -91,11 +169,13 @@ fn parse_blob(reader: &mut Read, header: &file::BlobHeader) -> Result<file::Blob
     ::read_message(reader, data_length as usize)
 }
 
-fn parse_data(blob: protos::file::Blob) -> Result<Vec<u8>, PbfParseError> {
-    if blob.has_zlib_data() {
+fn parse_data(mut blob: protos::file::Blob) -> Result<Vec<u8>, PbfParseError> {
+    if blob.has_raw() {
+        Ok(blob.take_raw())
+    } else if blob.has_zlib_data() {
         let mut inflated: Vec<u8> = vec![];
         let mut decoder = ZlibDecoder::new(blob.get_zlib_data());
-        decoder.read_to_end(&mut inflated)?;
+        decoder.read_to_end(&mut inflated).map_err(PbfParseError::Decompression)?;
         Ok(inflated)
     } else {
         Err(PbfParseError::InvalidBlobFormat)
@@ -115,7 +195,7 @@ impl<'a> TryFrom<&'a str> for BlobType {
         match value {
             "OSMHeader" => Ok(BlobType::HEADER),
             "OSMData" => Ok(BlobType::DATA),
-            _ => Err(PbfParseError::InvalidBlobType)
+            _ => Err(PbfParseError::InvalidBlobType(value.to_string()))
         }
     }
 }
@@ -0,0 +1,261 @@
+use ::PbfParseError;
+use extract::Region;
+use geometry::{BoundingBox, Polygon};
+use location::Location;
+use serde_json::{self, Value};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// The number of rows and columns of the grid used to avoid full point-in-polygon tests
+const GRID_SIZE: usize = 64;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Cell {
+    Outside,
+    Inside,
+    /// Crossed by the polygon's boundary, so locations in it need a full test
+    Boundary,
+}
+
+/// A region bounded by one or more polygons, read from an Osmosis `.poly` file or a GeoJSON (multi)polygon.
+///
+/// The bounding box of the region is divided into a grid, with each cell marked as inside, outside or on the
+/// boundary, so that only locations near the boundary need a full test. Those are tested against just the edges
+/// crossing their row of the grid.
+#[derive(Debug, Clone)]
+pub struct PolygonRegion {
+    polygons: Vec<Polygon>,
+    bbox: BoundingBox,
+    edges: Vec<(Location, Location)>,
+    /// The indices of the edges crossing each row of the grid
+    rows: Vec<Vec<usize>>,
+    cells: Vec<Cell>,
+}
+
+impl PolygonRegion {
+    pub fn new(polygons: Vec<Polygon>) -> Result<PolygonRegion, PbfParseError> {
+        let locations: Vec<Location> = polygons.iter().flat_map(|polygon| polygon.exterior.iter().cloned()).collect();
+        let bbox = match BoundingBox::from_locations(&locations) {
+            Some(bbox) => bbox,
+            None => return Err(PbfParseError::MalformedData("boundary has no polygons".to_string())),
+        };
+
+        let mut edges = Vec::new();
+        for polygon in &polygons {
+            for ring in Some(&polygon.exterior).into_iter().chain(polygon.interiors.iter()) {
+                edges.extend(ring.windows(2).map(|window| (window[0], window[1])));
+            }
+        }
+
+        let mut region = PolygonRegion {
+            polygons,
+            bbox,
+            edges,
+            rows: vec![Vec::new(); GRID_SIZE],
+            cells: vec![Cell::Outside; GRID_SIZE * GRID_SIZE],
+        };
+        region.build_grid();
+        Ok(region)
+    }
+
+    /// Reads a boundary from a `.poly` file, or from a GeoJSON file if the name ends in `.json` or `.geojson`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<PolygonRegion, PbfParseError> {
+        let path = path.as_ref();
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") | Some("geojson") => PolygonRegion::from_geojson(&text),
+            _ => PolygonRegion::from_poly(&text),
+        }
+    }
+
+    /// Parses an Osmosis polygon filter file: a name line, then sections of `longitude latitude` lines that each
+    /// end with `END`, and a final `END`. Sections whose name starts with `!` are holes in the preceding polygon.
+    pub fn from_poly(text: &str) -> Result<PolygonRegion, PbfParseError> {
+        let malformed = |message: &str| PbfParseError::MalformedData(format!("invalid poly file: {}", message));
+
+        let mut lines = text.lines().map(|line| line.trim()).filter(|line| !line.is_empty()).skip(1);
+        let mut polygons: Vec<Polygon> = Vec::new();
+        loop {
+            let section = lines.next().ok_or_else(|| malformed("missing final END"))?;
+            if section == "END" {
+                break;
+            }
+
+            let mut ring = Vec::new();
+            loop {
+                let line = lines.next().ok_or_else(|| malformed("unterminated section"))?;
+                if line == "END" {
+                    break;
+                }
+                let mut values = line.split_whitespace().map(|value| value.parse::<f64>());
+                match (values.next(), values.next()) {
+                    (Some(Ok(longitude)), Some(Ok(latitude))) => ring.push(Location::new(latitude, longitude)),
+                    _ => return Err(malformed(&format!("bad coordinate line '{}'", line))),
+                }
+            }
+            let ring = close_ring(ring).ok_or_else(|| malformed(&format!("section '{}' has too few points", section)))?;
+
+            if section.starts_with('!') {
+                let polygon = polygons.pop().ok_or_else(|| malformed("hole before any polygon"))?;
+                let mut interiors = polygon.interiors;
+                interiors.push(ring);
+                polygons.push(Polygon::new(polygon.exterior, interiors));
+            } else {
+                polygons.push(Polygon::new(ring, Vec::new()));
+            }
+        }
+        PolygonRegion::new(polygons)
+    }
+
+    /// Parses a GeoJSON Polygon or MultiPolygon, or a Feature or FeatureCollection of them.
+    pub fn from_geojson(text: &str) -> Result<PolygonRegion, PbfParseError> {
+        let value: Value = serde_json::from_str(text)
+            .map_err(|error| PbfParseError::MalformedData(format!("invalid GeoJSON: {}", error)))?;
+        let mut polygons = Vec::new();
+        collect_geojson_polygons(&value, &mut polygons)?;
+        PolygonRegion::new(polygons)
+    }
+
+    pub fn polygons(&self) -> &[Polygon] {
+        &self.polygons
+    }
+
+    fn build_grid(&mut self) {
+        for (index, &(a, b)) in self.edges.iter().enumerate() {
+            let (bottom, top) = (self.row(a.latitude.min(b.latitude)), self.row(a.latitude.max(b.latitude)));
+            let (left, right) = (self.column(a.longitude.min(b.longitude)), self.column(a.longitude.max(b.longitude)));
+            for row in bottom..top + 1 {
+                self.rows[row].push(index);
+                for column in left..right + 1 {
+                    self.cells[row * GRID_SIZE + column] = Cell::Boundary;
+                }
+            }
+        }
+
+        // Cells the boundary doesn't cross lie entirely inside or outside, so testing their centre is enough
+        let (cell_width, cell_height) = self.cell_size();
+        for row in 0..GRID_SIZE {
+            for column in 0..GRID_SIZE {
+                if self.cells[row * GRID_SIZE + column] == Cell::Boundary {
+                    continue;
+                }
+                let centre = Location::new(
+                    self.bbox.bottom + (row as f64 + 0.5) * cell_height,
+                    self.bbox.left + (column as f64 + 0.5) * cell_width,
+                );
+                self.cells[row * GRID_SIZE + column] = if self.contains_exact(row, centre) { Cell::Inside } else { Cell::Outside };
+            }
+        }
+    }
+
+    fn cell_size(&self) -> (f64, f64) {
+        ((self.bbox.right - self.bbox.left) / GRID_SIZE as f64, (self.bbox.top - self.bbox.bottom) / GRID_SIZE as f64)
+    }
+
+    fn row(&self, latitude: f64) -> usize {
+        grid_index(latitude, self.bbox.bottom, self.cell_size().1)
+    }
+
+    fn column(&self, longitude: f64) -> usize {
+        grid_index(longitude, self.bbox.left, self.cell_size().0)
+    }
+
+    /// Tests a location against the edges crossing its row with the even-odd rule.
+    fn contains_exact(&self, row: usize, location: Location) -> bool {
+        let mut inside = false;
+        for &index in &self.rows[row] {
+            let (a, b) = self.edges[index];
+            if (a.latitude > location.latitude) != (b.latitude > location.latitude) {
+                let crossing = a.longitude + (location.latitude - a.latitude) / (b.latitude - a.latitude) * (b.longitude - a.longitude);
+                if location.longitude < crossing {
+                    inside = !inside;
+                }
+            }
+        }
+        inside
+    }
+}
+
+impl Region for PolygonRegion {
+    fn bounding_box(&self) -> BoundingBox {
+        self.bbox
+    }
+
+    fn contains(&self, location: Location) -> bool {
+        if !self.bbox.contains(location) {
+            return false;
+        }
+        let row = self.row(location.latitude);
+        match self.cells[row * GRID_SIZE + self.column(location.longitude)] {
+            Cell::Outside => false,
+            Cell::Inside => true,
+            Cell::Boundary => self.contains_exact(row, location),
+        }
+    }
+}
+
+fn grid_index(value: f64, origin: f64, cell_size: f64) -> usize {
+    if cell_size <= 0.0 {
+        return 0;
+    }
+    let index = ((value - origin) / cell_size).floor();
+    (index.max(0.0) as usize).min(GRID_SIZE - 1)
+}
+
+/// Repeats the first location at the end if needed, or returns `None` if there are too few locations for a ring.
+fn close_ring(mut ring: Vec<Location>) -> Option<Vec<Location>> {
+    if ring.len() < 3 {
+        return None;
+    }
+    if ring[0] != ring[ring.len() - 1] {
+        let first = ring[0];
+        ring.push(first);
+    }
+    if ring.len() < 4 {
+        return None;
+    }
+    Some(ring)
+}
+
+fn collect_geojson_polygons(value: &Value, polygons: &mut Vec<Polygon>) -> Result<(), PbfParseError> {
+    let malformed = |message: &str| PbfParseError::MalformedData(format!("invalid GeoJSON boundary: {}", message));
+    match value["type"].as_str() {
+        Some("FeatureCollection") => {
+            let features = value["features"].as_array().ok_or_else(|| malformed("features is not an array"))?;
+            for feature in features {
+                collect_geojson_polygons(feature, polygons)?;
+            }
+        }
+        Some("Feature") => collect_geojson_polygons(&value["geometry"], polygons)?,
+        Some("Polygon") => polygons.push(parse_geojson_polygon(&value["coordinates"])?),
+        Some("MultiPolygon") => {
+            let members = value["coordinates"].as_array().ok_or_else(|| malformed("coordinates is not an array"))?;
+            for member in members {
+                polygons.push(parse_geojson_polygon(member)?);
+            }
+        }
+        Some(other) => return Err(malformed(&format!("unsupported type '{}'", other))),
+        None => return Err(malformed("missing type")),
+    }
+    Ok(())
+}
+
+fn parse_geojson_polygon(coordinates: &Value) -> Result<Polygon, PbfParseError> {
+    let malformed = |message: &str| PbfParseError::MalformedData(format!("invalid GeoJSON polygon: {}", message));
+    let rings = coordinates.as_array().ok_or_else(|| malformed("coordinates is not an array"))?;
+    let mut rings = rings.iter().map(|ring| {
+        let positions = ring.as_array().ok_or_else(|| malformed("ring is not an array"))?;
+        let locations = positions.iter()
+            .map(|position| match (position[0].as_f64(), position[1].as_f64()) {
+                (Some(longitude), Some(latitude)) => Ok(Location::new(latitude, longitude)),
+                _ => Err(malformed("bad position")),
+            })
+            .collect::<Result<Vec<Location>, PbfParseError>>()?;
+        close_ring(locations).ok_or_else(|| malformed("ring has too few positions"))
+    });
+    let exterior = rings.next().ok_or_else(|| malformed("polygon has no rings"))??;
+    let interiors = rings.collect::<Result<Vec<_>, _>>()?;
+    Ok(Polygon::new(exterior, interiors))
+}
@@ -0,0 +1,394 @@
+use ::PbfParseError;
+use osm::{Entity, EntityInfo, MemberReference, NodeReference, OsmEntityType, OsmReader};
+use protos::osm::HeaderBlock;
+use reader::ErrorSummary;
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+use std::io::{Read, Seek};
+use std::iter::Peekable;
+use std::str::FromStr;
+use visitor::{ErrorPolicy, Flow, OsmVisitor, VisitResult};
+use xml::attribute::OwnedAttribute;
+use xml::reader::{EventReader, XmlEvent};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ChangeAction {
+    Create,
+    Modify,
+    Delete,
+}
+
+/// A single entity change from an osmChange file. Deleted entities carry the version of the deletion, and
+/// usually no tags, nodes or members.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub action: ChangeAction,
+    pub entity: Entity,
+}
+
+/// Parses an osmChange file, returning its changes in the order they appear.
+///
+/// User names are not kept, since entity info refers to users by their index in a block's string table, which
+/// changes don't have.
+pub fn parse_changes<R: Read>(input: R) -> Result<Vec<Change>, PbfParseError> {
+    let mut changes = Vec::new();
+    let mut action = None;
+    let mut current: Option<Entity> = None;
+    for event in EventReader::new(input) {
+        match event.map_err(|e| PbfParseError::MalformedData(format!("invalid osmChange: {}", e)))? {
+            XmlEvent::StartElement { name, attributes, .. } => match name.local_name.as_str() {
+                "create" => action = Some(ChangeAction::Create),
+                "modify" => action = Some(ChangeAction::Modify),
+                "delete" => action = Some(ChangeAction::Delete),
+                element @ "node" | element @ "way" | element @ "relation" => {
+                    if action.is_none() {
+                        return Err(PbfParseError::MalformedData(format!("{} outside of create, modify or delete", element)));
+                    }
+                    current = Some(parse_entity(element, &attributes)?);
+                }
+                "tag" => {
+                    if let Some(ref mut entity) = current {
+                        let tag = (attribute(&attributes, "tag", "k")?.to_string(), attribute(&attributes, "tag", "v")?.to_string());
+                        match *entity {
+                            Entity::Node { ref mut tags, .. } | Entity::Way { ref mut tags, .. } | Entity::Relation { ref mut tags, .. } => tags.push(tag),
+                        }
+                    }
+                }
+                "nd" => {
+                    if let Some(Entity::Way { ref mut nodes, .. }) = current {
+                        nodes.push(NodeReference { id: parse_attribute(&attributes, "nd", "ref")? });
+                    }
+                }
+                "member" => {
+                    if let Some(Entity::Relation { ref mut members, .. }) = current {
+                        let entity_type = match attribute(&attributes, "member", "type")? {
+                            "node" => OsmEntityType::Node,
+                            "way" => OsmEntityType::Way,
+                            "relation" => OsmEntityType::Relation,
+                            other => return Err(PbfParseError::MalformedData(format!("unknown member type '{}'", other))),
+                        };
+                        let id = parse_attribute(&attributes, "member", "ref")?;
+                        let role = attribute(&attributes, "member", "role").unwrap_or("").to_string();
+                        members.push(MemberReference { id, entity_type, role });
+                    }
+                }
+                _ => (),
+            },
+            XmlEvent::EndElement { name } => match name.local_name.as_str() {
+                "create" | "modify" | "delete" => action = None,
+                "node" | "way" | "relation" => {
+                    if let (Some(action), Some(entity)) = (action, current.take()) {
+                        changes.push(Change { action, entity });
+                    }
+                }
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+    Ok(changes)
+}
+
+fn parse_entity(element: &str, attributes: &[OwnedAttribute]) -> Result<Entity, PbfParseError> {
+    let id = parse_attribute(attributes, element, "id")?;
+    let timestamp = match attribute(attributes, element, "timestamp") {
+        Ok(text) => parse_timestamp(text).ok_or_else(|| PbfParseError::MalformedData(format!("invalid timestamp '{}'", text)))?,
+        Err(_) => 0,
+    };
+    let info = EntityInfo {
+        version: parse_attribute(attributes, element, "version").unwrap_or(-1),
+        timestamp,
+        changeset: parse_attribute(attributes, element, "changeset").unwrap_or(0),
+        uid: parse_attribute(attributes, element, "uid").unwrap_or(0),
+        user_sid: 0,
+        visible: parse_attribute(attributes, element, "visible").unwrap_or(true),
+    };
+    Ok(match element {
+        "node" => {
+            // Deleted nodes may be given without a location
+            let latitude = parse_attribute(attributes, element, "lat").unwrap_or(0.0);
+            let longitude = parse_attribute(attributes, element, "lon").unwrap_or(0.0);
+            Entity::Node { id, latitude, longitude, tags: Vec::new(), info }
+        }
+        "way" => Entity::Way { id, nodes: Vec::new(), tags: Vec::new(), info },
+        _ => Entity::Relation { id, members: Vec::new(), tags: Vec::new(), info },
+    })
+}
+
+fn attribute<'a>(attributes: &'a [OwnedAttribute], element: &str, name: &str) -> Result<&'a str, PbfParseError> {
+    attributes.iter()
+        .find(|attribute| attribute.name.local_name == name)
+        .map(|attribute| attribute.value.as_str())
+        .ok_or_else(|| PbfParseError::MalformedData(format!("{} without {} attribute", element, name)))
+}
+
+fn parse_attribute<T: FromStr>(attributes: &[OwnedAttribute], element: &str, name: &str) -> Result<T, PbfParseError> {
+    let text = attribute(attributes, element, name)?;
+    text.parse().map_err(|_| PbfParseError::MalformedData(format!("invalid {} attribute '{}' on {}", name, text, element)))
+}
+
+/// Parses a UTC timestamp such as `2018-06-01T12:00:02Z` into milliseconds since the epoch.
+fn parse_timestamp(text: &str) -> Option<i64> {
+    let mut parts = text.trim_end_matches('Z').splitn(2, 'T');
+    let date: Vec<i64> = parts.next()?.split('-').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    let time: Vec<i64> = parts.next()?.split(':').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    if date.len() != 3 || time.len() != 3 || date[1] < 1 || date[1] > 12 || date[2] < 1 || date[2] > 31 {
+        return None;
+    }
+    let days = days_from_civil(date[0], date[1], date[2]);
+    Some((days * 86_400 + time[0] * 3600 + time[1] * 60 + time[2]) * 1000)
+}
+
+/// The number of days from 1970-01-01 to a date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Counting years from March puts the leap day at the end of the year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The position of a diff in a replication stream, as stored in the `state.txt` file next to it.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ReplicationState {
+    pub sequence_number: i64,
+    /// Seconds since the epoch
+    pub timestamp: i64,
+}
+
+impl ReplicationState {
+    pub fn parse(text: &str) -> Result<ReplicationState, PbfParseError> {
+        let mut sequence_number = None;
+        let mut timestamp = None;
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            let mut parts = line.splitn(2, '=');
+            match (parts.next().map(str::trim), parts.next()) {
+                (Some("sequenceNumber"), Some(value)) => sequence_number = value.trim().parse().ok(),
+                // Colons are escaped in these Java properties files
+                (Some("timestamp"), Some(value)) => timestamp = parse_timestamp(&value.trim().replace('\\', "")).map(|time| time / 1000),
+                _ => (),
+            }
+        }
+        match (sequence_number, timestamp) {
+            (Some(sequence_number), Some(timestamp)) => Ok(ReplicationState { sequence_number, timestamp }),
+            _ => Err(PbfParseError::MalformedData("replication state without a valid sequence number and timestamp".to_string())),
+        }
+    }
+}
+
+/// What applying changes did to a file.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct ChangeSummary {
+    pub created: usize,
+    pub modified: usize,
+    pub deleted: usize,
+    /// Changes that had no effect, being older than the version in the file or deleting an entity not in it
+    pub skipped: usize,
+}
+
+/// Applies osmChange diffs to a file sorted by type and then ID, writing the updated file.
+///
+/// Changes are kept in memory, sorted by entity, and merged into the file as it is streamed through, so the
+/// output stays sorted. When several changes affect an entity, the newest is applied, and a change is only
+/// applied over an entity in the file if its version isn't lower, so reapplying an old diff does nothing. The
+/// output header carries the replication state if one is set, and otherwise the latest timestamp of the
+/// changes as its replication timestamp.
+///
+/// Fails with `PbfParseError::MalformedData` if the input turns out not to be sorted.
+pub struct ChangeApplier {
+    changes: BTreeMap<(OsmEntityType, i64), Change>,
+    state: Option<ReplicationState>,
+}
+
+impl ChangeApplier {
+    pub fn new() -> ChangeApplier {
+        ChangeApplier { changes: BTreeMap::new(), state: None }
+    }
+
+    /// Adds the changes of a diff, which should be added in order if several affect the same entity with the
+    /// same version.
+    pub fn add_changes(&mut self, changes: Vec<Change>) {
+        for change in changes {
+            let key = (change.entity.entity_type(), change.entity.id());
+            let info = change.entity.info();
+            let newer = self.changes.get(&key).map_or(true, |previous| {
+                let previous_info = previous.entity.info();
+                (info.version, info.timestamp) >= (previous_info.version, previous_info.timestamp)
+            });
+            if newer {
+                self.changes.insert(key, change);
+            }
+        }
+    }
+
+    /// Sets the replication state written to the output header, usually that of the last diff applied.
+    pub fn set_replication_state(&mut self, state: ReplicationState) {
+        self.state = Some(state);
+    }
+
+    pub fn run<T: Read + Seek>(&self, reader: &mut OsmReader<T>, output: &mut OsmVisitor) -> Result<(ChangeSummary, ErrorSummary), PbfParseError> {
+        let mut visitor = ApplyVisitor {
+            applier: self,
+            output,
+            pending: self.changes.iter().peekable(),
+            last: None,
+            stopped: false,
+            summary: ChangeSummary::default(),
+        };
+        let errors = reader.accept(&mut visitor)?;
+        Ok((visitor.summary, errors))
+    }
+
+    fn edit_header(&self, header: &mut HeaderBlock) {
+        match self.state {
+            Some(state) => {
+                header.set_osmosis_replication_timestamp(state.timestamp);
+                header.set_osmosis_replication_sequence_number(state.sequence_number);
+            }
+            None => {
+                let latest = self.changes.values().map(|change| change.entity.info().timestamp / 1000).max();
+                if let Some(latest) = latest {
+                    if latest > header.get_osmosis_replication_timestamp() {
+                        header.set_osmosis_replication_timestamp(latest);
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct ApplyVisitor<'a> {
+    applier: &'a ChangeApplier,
+    output: &'a mut OsmVisitor,
+    pending: Peekable<btree_map::Iter<'a, (OsmEntityType, i64), Change>>,
+    last: Option<(OsmEntityType, i64)>,
+    stopped: bool,
+    summary: ChangeSummary,
+}
+
+impl<'a> ApplyVisitor<'a> {
+    /// Writes an entity from the file, first writing any changes to entities before it and replacing it with
+    /// its own change if there is one.
+    fn apply(&mut self, entity: Entity) -> VisitResult {
+        let key = (entity.entity_type(), entity.id());
+        if let Some(last) = self.last {
+            if key <= last {
+                return Err(PbfParseError::MalformedData(format!(
+                    "input is not sorted: {} {} follows {} {}", key.0, key.1, last.0, last.1
+                )));
+            }
+        }
+        self.last = Some(key);
+
+        if self.write_pending(Some(key))? == Flow::Stop {
+            return self.stop();
+        }
+        let change = match self.pending.peek() {
+            Some(&(pending_key, change)) if *pending_key == key => Some(change),
+            _ => None,
+        };
+        let flow = match change {
+            Some(change) => {
+                self.pending.next();
+                let version = change.entity.info().version;
+                if version >= 0 && version < entity.info().version {
+                    self.summary.skipped += 1;
+                    entity.visit(self.output)?
+                } else if change.action == ChangeAction::Delete {
+                    self.summary.deleted += 1;
+                    Flow::Continue
+                } else {
+                    self.summary.modified += 1;
+                    change.entity.clone().visit(self.output)?
+                }
+            }
+            None => entity.visit(self.output)?,
+        };
+        if flow == Flow::Stop {
+            return self.stop();
+        }
+        Ok(Flow::Continue)
+    }
+
+    /// Writes the changes to entities not in the file that come before the given key, or all of them.
+    fn write_pending(&mut self, before: Option<(OsmEntityType, i64)>) -> VisitResult {
+        while let Some(&(key, change)) = self.pending.peek() {
+            if before.map_or(false, |before| *key >= before) {
+                break;
+            }
+            self.pending.next();
+            if change.action == ChangeAction::Delete {
+                self.summary.skipped += 1;
+                continue;
+            }
+            self.summary.created += 1;
+            if change.entity.clone().visit(self.output)? == Flow::Stop {
+                return Ok(Flow::Stop);
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn stop(&mut self) -> VisitResult {
+        self.stopped = true;
+        Ok(Flow::Stop)
+    }
+}
+
+impl<'a> OsmVisitor for ApplyVisitor<'a> {
+    fn visit_header(&mut self, block: &HeaderBlock) -> VisitResult {
+        let mut header = block.clone();
+        self.applier.edit_header(&mut header);
+        self.output.visit_header(&header)
+    }
+
+    fn visit_node(&mut self, id: i64, latitude: f64, longitude: f64, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+        self.apply(Entity::Node { id, latitude, longitude, tags, info })
+    }
+
+    fn visit_way(&mut self, id: i64, nodes: Vec<NodeReference>, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+        self.apply(Entity::Way { id, nodes, tags, info })
+    }
+
+    fn visit_relation(&mut self, id: i64, members: Vec<MemberReference>, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+        self.apply(Entity::Relation { id, members, tags, info })
+    }
+
+    /// Writes the changes to entities after the last one in the file.
+    fn end(&mut self) -> Result<(), PbfParseError> {
+        if !self.stopped {
+            self.write_pending(None)?;
+        }
+        self.output.end()
+    }
+
+    /// Skipping a blob would silently drop its entities from the updated file, so any error aborts.
+    fn handle_error(&mut self, _error: &PbfParseError) -> ErrorPolicy {
+        ErrorPolicy::Abort
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_timestamp("2000-02-29T00:00:00Z"), Some(951_782_400_000));
+        assert_eq!(parse_timestamp("2000-03-01T00:00:00Z"), Some(951_868_800_000));
+        assert_eq!(parse_timestamp("1969-12-31T00:00:00Z"), Some(-86_400_000));
+        assert_eq!(parse_timestamp("2018-06-01"), None);
+        assert_eq!(parse_timestamp("2018-06-01T12:xx:02Z"), None);
+    }
+
+    #[test]
+    fn parses_replication_state() {
+        let text = "#Fri Jun 01 12:00:05 UTC 2018\nsequenceNumber=2940\ntimestamp=2018-06-01T12\\:00\\:02Z\n";
+        let state = ReplicationState::parse(text).unwrap();
+        assert_eq!(state.sequence_number, 2940);
+        assert_eq!(state.timestamp, 1_527_854_402);
+    }
+}
@@ -0,0 +1,269 @@
+use ::PbfParseError;
+use geojson::GeoJsonWriter;
+use geometry::{Chain, join_chains, Polygon, signed_area};
+use location::{Location, LocationResolver, NodeLocationStore, WayGeometry, WayGeometryVisitor};
+use osm::OsmReader;
+use reader::ErrorSummary;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek, Write};
+use visitor::{Flow, VisitResult};
+
+/// Coastline ways joined end to start, following the OSM convention that land lies to the left of a coastline.
+#[derive(Debug, Clone, Default)]
+pub struct Coastline {
+    /// Chains that closed into rings. Correctly oriented rings are counter-clockwise
+    pub rings: Vec<Chain>,
+    /// Chains that could not be closed
+    pub open: Vec<Chain>,
+    pub problems: Vec<CoastlineProblem>,
+}
+
+/// A problem found while joining coastline ways.
+#[derive(Debug, Clone)]
+pub enum CoastlineProblem {
+    /// Some nodes of a coastline way are not present in the node location store
+    MissingNodes { way_id: i64, node_ids: Vec<i64> },
+    /// A chain of ways does not close into a ring
+    OpenEnds { ways: Vec<i64>, start_node: i64, start: Location, end_node: i64, end: Location },
+    /// A chain meets another chain head to head or tail to tail, so one of them likely runs the wrong way
+    ReversedSegment { ways: Vec<i64>, node_id: i64, location: Location },
+    /// A closed ring runs clockwise, putting the land on the wrong side
+    ReversedRing { ways: Vec<i64>, location: Location },
+    /// Two ways share a segment between the same pair of nodes
+    DuplicateOverlap { way_ids: (i64, i64), node_ids: (i64, i64), location: Location },
+}
+
+impl CoastlineProblem {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CoastlineProblem::MissingNodes { .. } => "missing_nodes",
+            CoastlineProblem::OpenEnds { .. } => "open_ends",
+            CoastlineProblem::ReversedSegment { .. } => "reversed_segment",
+            CoastlineProblem::ReversedRing { .. } => "reversed_ring",
+            CoastlineProblem::DuplicateOverlap { .. } => "duplicate_overlap",
+        }
+    }
+}
+
+impl Coastline {
+    /// The closed rings as polygons, leaving out reversed rings.
+    pub fn polygons(&self) -> Vec<Polygon> {
+        self.rings.iter()
+            .map(|ring| ring.locations())
+            .filter(|locations| signed_area(locations) > 0.0)
+            .map(|locations| Polygon::new(locations, Vec::new()))
+            .collect()
+    }
+
+    /// Writes rings as polygons, open chains as lines and problems as points to a GeoJSON file for inspection.
+    pub fn write_geojson(&self, output: &mut Write) -> Result<(), PbfParseError> {
+        let mut writer = GeoJsonWriter::new(output)?;
+        for ring in &self.rings {
+            let locations = ring.locations();
+            let kind = if signed_area(&locations) > 0.0 { "ring" } else { "reversed_ring" };
+            let properties = [("kind", kind.to_string()), ("ways", format_ids(&ring.way_ids()))];
+            // Keep the ring as it is, since its direction is what is being inspected
+            writer.write_polygon(&Polygon { exterior: locations, interiors: Vec::new() }, &properties)?;
+        }
+        for chain in &self.open {
+            let properties = [("kind", "open".to_string()), ("ways", format_ids(&chain.way_ids()))];
+            writer.write_line(&chain.locations(), &properties)?;
+        }
+        for problem in &self.problems {
+            let (location, ways) = match *problem {
+                CoastlineProblem::MissingNodes { .. } => continue,
+                CoastlineProblem::OpenEnds { ref ways, end, .. } => (end, format_ids(ways)),
+                CoastlineProblem::ReversedSegment { ref ways, location, .. } => (location, format_ids(ways)),
+                CoastlineProblem::ReversedRing { ref ways, location } => (location, format_ids(ways)),
+                CoastlineProblem::DuplicateOverlap { way_ids: (a, b), location, .. } => (location, format_ids(&[a, b])),
+            };
+            writer.write_point(location, &[("kind", problem.name().to_string()), ("ways", ways)])?;
+        }
+        writer.finish()
+    }
+}
+
+pub fn is_coastline(tags: &[(String, String)]) -> bool {
+    tags.iter().any(|(k, v)| k == "natural" && v == "coastline")
+}
+
+/// Joins `natural=coastline` ways into rings. Node locations are stored and ways resolved in a single pass, so
+/// the input must have its nodes before its ways.
+pub struct CoastlineAssembler<'a> {
+    store: &'a mut NodeLocationStore,
+}
+
+impl<'a> CoastlineAssembler<'a> {
+    pub fn new(store: &'a mut NodeLocationStore) -> CoastlineAssembler<'a> {
+        CoastlineAssembler { store }
+    }
+
+    pub fn run<T: Read + Seek>(&mut self, reader: &mut OsmReader<T>) -> Result<(Coastline, ErrorSummary), PbfParseError> {
+        let mut collector = CoastlineCollector { ways: Vec::new() };
+        let summary = {
+            let mut resolver = LocationResolver::new(self.store, &mut collector);
+            reader.accept(&mut resolver)?
+        };
+        Ok((assemble(collector.ways), summary))
+    }
+}
+
+struct CoastlineCollector {
+    ways: Vec<WayGeometry>,
+}
+
+impl WayGeometryVisitor for CoastlineCollector {
+    fn visit_way_geometry(&mut self, mut way: WayGeometry) -> VisitResult {
+        if is_coastline(&way.tags) {
+            way.tags = Vec::new();
+            self.ways.push(way);
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// Joins coastline ways in their own direction and reports where they don't fit together.
+pub fn assemble(ways: Vec<WayGeometry>) -> Coastline {
+    let mut coastline = Coastline::default();
+
+    find_overlaps(&ways, &mut coastline.problems);
+
+    let mut chains = Vec::with_capacity(ways.len());
+    for way in ways {
+        // Joining only the nodes that were found would close rings across the gaps, so the way is left out and
+        // the chains it would have connected are reported as open
+        if !way.is_complete() {
+            coastline.problems.push(CoastlineProblem::MissingNodes { way_id: way.id, node_ids: way.missing });
+            continue;
+        }
+        chains.push(Chain::from_way(way.id, way.nodes));
+    }
+
+    for chain in join_chains(chains, false) {
+        if chain.is_closed() {
+            let locations = chain.locations();
+            if signed_area(&locations) < 0.0 {
+                coastline.problems.push(CoastlineProblem::ReversedRing { ways: chain.way_ids(), location: locations[0] });
+            }
+            coastline.rings.push(chain);
+        } else {
+            coastline.open.push(chain);
+        }
+    }
+
+    find_reversed_segments(&coastline.open, &mut coastline.problems);
+    for chain in &coastline.open {
+        coastline.problems.push(CoastlineProblem::OpenEnds {
+            ways: chain.way_ids(),
+            start_node: chain.first_node(),
+            start: chain.nodes[0].1,
+            end_node: chain.last_node(),
+            end: chain.nodes[chain.nodes.len() - 1].1,
+        });
+    }
+
+    coastline
+}
+
+/// Reports each pair of ways sharing a segment, in either direction, once. Ways with missing nodes are skipped,
+/// since the nodes found around a gap don't form a segment.
+fn find_overlaps(ways: &[WayGeometry], problems: &mut Vec<CoastlineProblem>) {
+    let mut segments: HashMap<(i64, i64), i64> = HashMap::new();
+    let mut reported: HashSet<(i64, i64)> = HashSet::new();
+    for way in ways.iter().filter(|way| way.is_complete()) {
+        for window in way.nodes.windows(2) {
+            let (a, b) = (window[0].0, window[1].0);
+            if a == b {
+                continue;
+            }
+            let key = (a.min(b), a.max(b));
+            match segments.get(&key) {
+                Some(&other) if other != way.id => {
+                    if reported.insert((other, way.id)) {
+                        problems.push(CoastlineProblem::DuplicateOverlap { way_ids: (other, way.id), node_ids: (a, b), location: window[0].1 });
+                    }
+                }
+                Some(_) => (),
+                None => {
+                    segments.insert(key, way.id);
+                }
+            }
+        }
+    }
+}
+
+/// Open chains that directional joining left meeting head to head or tail to tail would join if one of them were
+/// reversed. Of each such pair, the chain meeting others at both of its ends is reported, being the more likely to
+/// have been drawn the wrong way, or failing that the shorter one.
+fn find_reversed_segments(open: &[Chain], problems: &mut Vec<CoastlineProblem>) {
+    let mut starts: HashMap<i64, Vec<usize>> = HashMap::new();
+    let mut ends: HashMap<i64, Vec<usize>> = HashMap::new();
+    for (index, chain) in open.iter().enumerate() {
+        starts.entry(chain.first_node()).or_insert_with(Vec::new).push(index);
+        ends.entry(chain.last_node()).or_insert_with(Vec::new).push(index);
+    }
+    let conflicts: Vec<usize> = open.iter()
+        .map(|chain| (starts[&chain.first_node()].len() > 1) as usize + (ends[&chain.last_node()].len() > 1) as usize)
+        .collect();
+
+    let mut reported = HashSet::new();
+    for (a, chain) in open.iter().enumerate() {
+        let endpoints = [(chain.nodes[0], &starts), (chain.nodes[chain.nodes.len() - 1], &ends)];
+        for &((node_id, location), endpoint_map) in &endpoints {
+            for &b in endpoint_map[&node_id].iter().filter(|b| **b > a) {
+                let suspect = match conflicts[b].cmp(&conflicts[a]) {
+                    Ordering::Greater => b,
+                    Ordering::Less => a,
+                    Ordering::Equal => if open[b].nodes.len() < chain.nodes.len() { b } else { a },
+                };
+                if reported.insert(suspect) {
+                    problems.push(CoastlineProblem::ReversedSegment { ways: open[suspect].way_ids(), node_id, location });
+                }
+            }
+        }
+    }
+}
+
+fn format_ids(ids: &[i64]) -> String {
+    ids.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use osm::EntityInfo;
+
+    fn way(id: i64, node_ids: &[i64], missing: &[i64]) -> WayGeometry {
+        // Nodes lie on a counter-clockwise square, numbered from 1
+        let corners = [(0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (1.0, 0.0)];
+        let nodes = node_ids.iter()
+            .map(|&node| (node, Location::new(corners[(node - 1) as usize % 4].0, corners[(node - 1) as usize % 4].1)))
+            .collect();
+        let info = EntityInfo { version: 1, timestamp: 0, changeset: 0, uid: 0, user_sid: 0, visible: true };
+        WayGeometry { id, nodes, missing: missing.to_vec(), tags: Vec::new(), info }
+    }
+
+    #[test]
+    fn closes_complete_ways_into_rings() {
+        let coastline = assemble(vec![way(1, &[1, 2, 3], &[]), way(2, &[3, 4, 1], &[])]);
+        assert_eq!(coastline.rings.len(), 1);
+        assert!(coastline.open.is_empty());
+        assert!(coastline.problems.is_empty());
+    }
+
+    #[test]
+    fn leaves_out_ways_with_missing_nodes() {
+        // Way 2 is missing node 4, so the nodes found would still close the ring from node 3 back to node 1
+        let coastline = assemble(vec![way(1, &[1, 2, 3], &[]), way(2, &[3, 1], &[4])]);
+        assert!(coastline.rings.is_empty());
+        assert_eq!(coastline.open.len(), 1);
+        assert_eq!(coastline.open[0].way_ids(), vec![1]);
+        let names: Vec<&str> = coastline.problems.iter().map(|problem| problem.name()).collect();
+        assert_eq!(names, vec!["missing_nodes", "open_ends"]);
+        match coastline.problems[0] {
+            CoastlineProblem::MissingNodes { way_id, ref node_ids } => assert_eq!((way_id, node_ids.clone()), (2, vec![4])),
+            ref problem => panic!("unexpected {:?}", problem),
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use ::PbfParseError;
+use osm::{BlockStrings, Entity, EntityInfo, MemberReference, NodeReference};
+use protos::osm::HeaderBlock;
+use visitor::{ErrorPolicy, Flow, OsmVisitor, VisitResult};
+
+/// Implements the `OsmVisitor` methods of a visitor wrapping a `parent` visitor by forwarding them to it, so that
+/// each combinator only implements what it changes. `structure` forwards the block, group and traversal events
+/// and error handling, `header` the file header, and `entities` the entities, either unchanged or, with
+/// `entities as method`, converted to an `Entity` and passed to the given method of the visitor.
+macro_rules! forward_to_parent {
+    (structure) => {
+        fn visit_block(&mut self, lat_offset: i64, lon_offset: i64, granularity: i32, date_granularity: i32) -> VisitResult {
+            self.parent.visit_block(lat_offset, lon_offset, granularity, date_granularity)
+        }
+
+        fn visit_string_table(&mut self, strings: &BlockStrings) -> VisitResult {
+            self.parent.visit_string_table(strings)
+        }
+
+        fn end_block(&mut self) -> Result<(), PbfParseError> {
+            self.parent.end_block()
+        }
+
+        fn visit_group(&mut self) -> VisitResult {
+            self.parent.visit_group()
+        }
+
+        fn end_group(&mut self) -> Result<(), PbfParseError> {
+            self.parent.end_group()
+        }
+
+        fn end(&mut self) -> Result<(), PbfParseError> {
+            self.parent.end()
+        }
+
+        fn handle_error(&mut self, error: &PbfParseError) -> ErrorPolicy {
+            self.parent.handle_error(error)
+        }
+    };
+    (header) => {
+        fn visit_header(&mut self, block: &HeaderBlock) -> VisitResult {
+            self.parent.visit_header(block)
+        }
+    };
+    (entities) => {
+        fn visit_node(&mut self, id: i64, latitude: f64, longitude: f64, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+            self.parent.visit_node(id, latitude, longitude, tags, info)
+        }
+
+        fn visit_way(&mut self, id: i64, nodes: Vec<NodeReference>, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+            self.parent.visit_way(id, nodes, tags, info)
+        }
+
+        fn visit_relation(&mut self, id: i64, members: Vec<MemberReference>, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+            self.parent.visit_relation(id, members, tags, info)
+        }
+    };
+    (entities as $method:ident) => {
+        fn visit_node(&mut self, id: i64, latitude: f64, longitude: f64, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+            self.$method(Entity::Node { id, latitude, longitude, tags, info })
+        }
+
+        fn visit_way(&mut self, id: i64, nodes: Vec<NodeReference>, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+            self.$method(Entity::Way { id, nodes, tags, info })
+        }
+
+        fn visit_relation(&mut self, id: i64, members: Vec<MemberReference>, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+            self.$method(Entity::Relation { id, members, tags, info })
+        }
+    };
+}
+
+/// Forwards only the entities accepted by a predicate to the parent visitor.
+pub struct FilterVisitor<'a, F: FnMut(&Entity) -> bool> {
+    parent: &'a mut OsmVisitor,
+    predicate: F,
+}
+
+impl<'a, F: FnMut(&Entity) -> bool> FilterVisitor<'a, F> {
+    pub fn new(parent: &'a mut OsmVisitor, predicate: F) -> FilterVisitor<'a, F> {
+        FilterVisitor { parent, predicate }
+    }
+
+    fn visit_entity(&mut self, entity: Entity) -> VisitResult {
+        if (self.predicate)(&entity) {
+            entity.visit(self.parent)
+        } else {
+            Ok(Flow::Continue)
+        }
+    }
+}
+
+impl<'a, F: FnMut(&Entity) -> bool> OsmVisitor for FilterVisitor<'a, F> {
+    forward_to_parent!(structure);
+    forward_to_parent!(header);
+    forward_to_parent!(entities as visit_entity);
+}
+
+/// Transforms every entity before forwarding it to the parent visitor. Returning `None` drops the entity.
+pub struct MapVisitor<'a, F: FnMut(Entity) -> Option<Entity>> {
+    parent: &'a mut OsmVisitor,
+    transform: F,
+}
+
+impl<'a, F: FnMut(Entity) -> Option<Entity>> MapVisitor<'a, F> {
+    pub fn new(parent: &'a mut OsmVisitor, transform: F) -> MapVisitor<'a, F> {
+        MapVisitor { parent, transform }
+    }
+
+    fn visit_entity(&mut self, entity: Entity) -> VisitResult {
+        match (self.transform)(entity) {
+            Some(entity) => entity.visit(self.parent),
+            None => Ok(Flow::Continue),
+        }
+    }
+}
+
+impl<'a, F: FnMut(Entity) -> Option<Entity>> OsmVisitor for MapVisitor<'a, F> {
+    forward_to_parent!(structure);
+    forward_to_parent!(header);
+    forward_to_parent!(entities as visit_entity);
+}
+
+/// Fans every event out to several visitors in a single pass. A visitor that returns `Flow::Stop` receives no
+/// further events other than `end`, so that it can still flush its output, and the traversal stops once all
+/// visitors have stopped.
+///
+/// An error from one visitor doesn't keep the others from seeing the event. The first error is returned once
+/// every visitor has been called, and only the visitors still running are asked how to handle it.
+pub struct TeeVisitor<'a> {
+    visitors: Vec<&'a mut OsmVisitor>,
+    stopped: Vec<bool>,
+}
+
+impl<'a> TeeVisitor<'a> {
+    pub fn new(visitors: Vec<&'a mut OsmVisitor>) -> TeeVisitor<'a> {
+        let stopped = vec![false; visitors.len()];
+        TeeVisitor { visitors, stopped }
+    }
+
+    fn visit_each<F: FnMut(&mut OsmVisitor) -> VisitResult>(&mut self, mut visit: F) -> VisitResult {
+        let mut first_error = None;
+        for (visitor, stopped) in self.visitors.iter_mut().zip(self.stopped.iter_mut()) {
+            if *stopped {
+                continue;
+            }
+            match visit(&mut **visitor) {
+                Ok(Flow::Stop) => *stopped = true,
+                Ok(Flow::Continue) => (),
+                Err(error) => {
+                    if first_error.is_none() {
+                        first_error = Some(error);
+                    }
+                }
+            }
+        }
+        if let Some(error) = first_error {
+            return Err(error);
+        }
+        if self.stopped.iter().all(|stopped| *stopped) {
+            Ok(Flow::Stop)
+        } else {
+            Ok(Flow::Continue)
+        }
+    }
+
+    /// Ends something for the visitors still running, or for all of them when ending the traversal.
+    fn end_each<F: FnMut(&mut OsmVisitor) -> Result<(), PbfParseError>>(&mut self, include_stopped: bool, mut end: F) -> Result<(), PbfParseError> {
+        let mut first_error = None;
+        for (visitor, stopped) in self.visitors.iter_mut().zip(self.stopped.iter()) {
+            if *stopped && !include_stopped {
+                continue;
+            }
+            if let Err(error) = end(&mut **visitor) {
+                if first_error.is_none() {
+                    first_error = Some(error);
+                }
+            }
+        }
+        match first_error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a> OsmVisitor for TeeVisitor<'a> {
+    fn visit_block(&mut self, lat_offset: i64, lon_offset: i64, granularity: i32, date_granularity: i32) -> VisitResult {
+        self.visit_each(|v| v.visit_block(lat_offset, lon_offset, granularity, date_granularity))
+    }
+
+    fn visit_string_table(&mut self, strings: &BlockStrings) -> VisitResult {
+        self.visit_each(|v| v.visit_string_table(strings))
+    }
+
+    fn end_block(&mut self) -> Result<(), PbfParseError> {
+        self.end_each(false, |v| v.end_block())
+    }
+
+    fn visit_group(&mut self) -> VisitResult {
+        self.visit_each(|v| v.visit_group())
+    }
+
+    fn end_group(&mut self) -> Result<(), PbfParseError> {
+        self.end_each(false, |v| v.end_group())
+    }
+
+    fn visit_node(&mut self, id: i64, latitude: f64, longitude: f64, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+        self.visit_each(|v| v.visit_node(id, latitude, longitude, tags.clone(), info))
+    }
+
+    fn visit_way(&mut self, id: i64, nodes: Vec<NodeReference>, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+        self.visit_each(|v| v.visit_way(id, nodes.clone(), tags.clone(), info))
+    }
+
+    fn visit_relation(&mut self, id: i64, members: Vec<MemberReference>, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+        self.visit_each(|v| v.visit_relation(id, members.clone(), tags.clone(), info))
+    }
+
+    fn visit_header(&mut self, block: &HeaderBlock) -> VisitResult {
+        self.visit_each(|v| v.visit_header(block))
+    }
+
+    fn end(&mut self) -> Result<(), PbfParseError> {
+        self.end_each(true, |v| v.end())
+    }
+
+    /// Asks every visitor still running, following the most severe policy returned.
+    fn handle_error(&mut self, error: &PbfParseError) -> ErrorPolicy {
+        let mut policy = ErrorPolicy::SkipElement;
+        for (visitor, _) in self.visitors.iter_mut().zip(self.stopped.iter()).filter(|&(_, stopped)| !*stopped) {
+            policy = match (policy, visitor.handle_error(error)) {
+                (ErrorPolicy::Abort, _) | (_, ErrorPolicy::Abort) => ErrorPolicy::Abort,
+                (ErrorPolicy::SkipBlob, _) | (_, ErrorPolicy::SkipBlob) => ErrorPolicy::SkipBlob,
+                _ => ErrorPolicy::SkipElement,
+            };
+        }
+        policy
+    }
+}
+
+/// Lets a function rewrite the file header before it is forwarded to the parent visitor, such as to set the
+/// bounding box of an extract.
+pub struct HeaderVisitor<'a, F: FnMut(&mut HeaderBlock)> {
+    parent: &'a mut OsmVisitor,
+    edit: F,
+}
+
+impl<'a, F: FnMut(&mut HeaderBlock)> HeaderVisitor<'a, F> {
+    pub fn new(parent: &'a mut OsmVisitor, edit: F) -> HeaderVisitor<'a, F> {
+        HeaderVisitor { parent, edit }
+    }
+}
+
+impl<'a, F: FnMut(&mut HeaderBlock)> OsmVisitor for HeaderVisitor<'a, F> {
+    forward_to_parent!(structure);
+    forward_to_parent!(entities);
+
+    fn visit_header(&mut self, block: &HeaderBlock) -> VisitResult {
+        let mut block = block.clone();
+        (self.edit)(&mut block);
+        self.parent.visit_header(&block)
+    }
+}
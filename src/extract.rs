@@ -0,0 +1,256 @@
+use ::PbfParseError;
+use combinator::{FilterVisitor, HeaderVisitor, TeeVisitor};
+use geometry::BoundingBox;
+use idset::IdSet;
+use location::Location;
+use osm::{EntityInfo, MemberReference, NANODEGREE_UNIT, NodeReference, OsmEntityType, OsmReader};
+use pipeline::{Selection, WayNodeVisitor};
+use protos::osm::HeaderBBox;
+use reader::ErrorSummary;
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+use visitor::{Flow, OsmVisitor, VisitResult};
+
+/// An area of the map to cut an extract from.
+pub trait Region {
+    /// A box around the whole region, written to the header of the extract.
+    fn bounding_box(&self) -> BoundingBox;
+
+    fn contains(&self, location: Location) -> bool;
+}
+
+impl<'a> Region for Box<Region + 'a> {
+    fn bounding_box(&self) -> BoundingBox {
+        (**self).bounding_box()
+    }
+
+    fn contains(&self, location: Location) -> bool {
+        (**self).contains(location)
+    }
+}
+
+impl Region for BoundingBox {
+    fn bounding_box(&self) -> BoundingBox {
+        *self
+    }
+
+    fn contains(&self, location: Location) -> bool {
+        BoundingBox::contains(self, location)
+    }
+}
+
+/// How much of what the entities inside a region reference is added to an extract.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ExtractStrategy {
+    /// Nodes inside the region, ways with at least one node inside it and relations with any of those nodes or
+    /// ways as members. Ways crossing the edge of the region reference nodes that are left out.
+    Simple,
+    /// As `Simple`, but with every node of the selected ways, and with the relations that have a selected
+    /// relation as member, recursively.
+    CompleteWays,
+    /// As `CompleteWays`, but selected relations of the complete types, multipolygons by default, also bring in
+    /// all of their member ways along with their nodes, so that areas crossing the edge stay whole.
+    Smart,
+}
+
+/// Cuts extracts of everything inside one or more regions. Entities are selected for every region at once in one
+/// pass, or two for the smart strategy, and written in another, with each region's bounding box set in the header
+/// of its output.
+pub struct Extract<R: Region> {
+    regions: Vec<R>,
+    strategy: ExtractStrategy,
+    complete_relation_types: Vec<String>,
+}
+
+impl<R: Region> Extract<R> {
+    pub fn new(region: R, strategy: ExtractStrategy) -> Extract<R> {
+        Extract::with_regions(vec![region], strategy)
+    }
+
+    pub fn with_regions(regions: Vec<R>, strategy: ExtractStrategy) -> Extract<R> {
+        Extract { regions, strategy, complete_relation_types: vec!["multipolygon".to_string()] }
+    }
+
+    /// Sets the relation types that the smart strategy completes.
+    pub fn set_complete_relation_types(&mut self, types: Vec<String>) {
+        self.complete_relation_types = types;
+    }
+
+    /// Selects the entities of each region's extract, returning them in the order of the regions along with the
+    /// errors skipped over all passes.
+    pub fn collect<T: Read + Seek>(&mut self, reader: &mut OsmReader<T>) -> Result<(Vec<Selection>, ErrorSummary), PbfParseError> {
+        let strategy = self.strategy;
+        let complete_relation_types = &self.complete_relation_types;
+        let mut region_visitors: Vec<RegionVisitor<R>> = self.regions.iter()
+            .map(|region| RegionVisitor { region, strategy, selection: Selection::default(), way_nodes: IdSet::new() })
+            .collect();
+        // How relations reference each other doesn't depend on the region, so it is collected once for all of them
+        let mut relations = RelationCollector {
+            strategy,
+            complete_relation_types,
+            parent_relations: HashMap::new(),
+            complete_candidates: HashMap::new(),
+        };
+        let mut summary = {
+            let mut visitors: Vec<&mut OsmVisitor> = region_visitors.iter_mut().map(|visitor| visitor as &mut OsmVisitor).collect();
+            visitors.push(&mut relations);
+            let mut tee = TeeVisitor::new(visitors);
+            reader.accept(&mut tee)?
+        };
+        let RelationCollector { parent_relations, complete_candidates, .. } = relations;
+
+        let mut selections = Vec::with_capacity(region_visitors.len());
+        let mut way_nodes = Vec::with_capacity(region_visitors.len());
+        let mut added_ways = Vec::with_capacity(region_visitors.len());
+        for visitor in region_visitors {
+            let RegionVisitor { mut selection, way_nodes: nodes, .. } = visitor;
+
+            // Relations can be members of relations that appear earlier in the file, so resolve parents in memory
+            let mut pending: Vec<i64> = selection.relations.iter().collect();
+            while let Some(id) = pending.pop() {
+                if let Some(parents) = parent_relations.get(&id) {
+                    for parent in parents {
+                        if selection.relations.insert(*parent) {
+                            pending.push(*parent);
+                        }
+                    }
+                }
+            }
+
+            let mut added = IdSet::new();
+            for (id, ways) in &complete_candidates {
+                if selection.relations.contains(*id) {
+                    for way in ways {
+                        if selection.ways.insert(*way) {
+                            added.insert(*way);
+                        }
+                    }
+                }
+            }
+
+            selections.push(selection);
+            way_nodes.push(nodes);
+            added_ways.push(added);
+        }
+
+        if added_ways.iter().any(|ways| !ways.is_empty()) {
+            let mut way_visitors: Vec<WayNodeVisitor> = added_ways.iter().zip(way_nodes.iter_mut())
+                .map(|(ways, nodes)| WayNodeVisitor::new(ways, nodes))
+                .collect();
+            let mut tee = TeeVisitor::new(way_visitors.iter_mut().map(|visitor| visitor as &mut OsmVisitor).collect());
+            summary.merge(reader.accept(&mut tee)?);
+        }
+
+        for (selection, nodes) in selections.iter_mut().zip(way_nodes.iter()) {
+            selection.nodes.union(nodes);
+        }
+        Ok((selections, summary))
+    }
+
+    /// Selects the entities of each region's extract and writes them to the output visitor at the same position
+    /// in a final pass.
+    ///
+    /// Panics if the number of outputs differs from the number of regions.
+    pub fn run<T: Read + Seek>(&mut self, reader: &mut OsmReader<T>, outputs: &mut [&mut OsmVisitor]) -> Result<(Vec<Selection>, ErrorSummary), PbfParseError> {
+        assert_eq!(outputs.len(), self.regions.len(), "an extract needs one output per region");
+
+        let (selections, mut summary) = self.collect(reader)?;
+        {
+            let mut headers: Vec<_> = outputs.iter_mut().zip(self.regions.iter())
+                .map(|(output, region)| {
+                    let bbox = header_bbox(&region.bounding_box());
+                    HeaderVisitor::new(&mut **output, move |block| block.set_bbox(bbox.clone()))
+                })
+                .collect();
+            let mut filters: Vec<_> = headers.iter_mut().zip(selections.iter())
+                .map(|(header, selection)| {
+                    FilterVisitor::new(header, move |entity| selection.contains(entity.entity_type(), entity.id()))
+                })
+                .collect();
+            let mut tee = TeeVisitor::new(filters.iter_mut().map(|filter| filter as &mut OsmVisitor).collect());
+            summary.merge(reader.accept(&mut tee)?);
+        }
+        Ok((selections, summary))
+    }
+}
+
+pub fn header_bbox(bbox: &BoundingBox) -> HeaderBBox {
+    let to_nanodegrees = |degrees: f64| (degrees / NANODEGREE_UNIT).round() as i64;
+    let mut header_bbox = HeaderBBox::new();
+    header_bbox.set_left(to_nanodegrees(bbox.left));
+    header_bbox.set_right(to_nanodegrees(bbox.right));
+    header_bbox.set_top(to_nanodegrees(bbox.top));
+    header_bbox.set_bottom(to_nanodegrees(bbox.bottom));
+    header_bbox
+}
+
+struct RegionVisitor<'a, R: 'a + Region> {
+    region: &'a R,
+    strategy: ExtractStrategy,
+    /// Holds only the nodes inside the region during the pass, since relations are selected by those
+    selection: Selection,
+    /// Nodes of selected ways that lie outside the region
+    way_nodes: IdSet,
+}
+
+impl<'a, R: 'a + Region> OsmVisitor for RegionVisitor<'a, R> {
+    fn visit_node(&mut self, id: i64, latitude: f64, longitude: f64, _tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        if self.region.contains(Location::new(latitude, longitude)) {
+            self.selection.nodes.insert(id);
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn visit_way(&mut self, id: i64, nodes: Vec<NodeReference>, _tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        if nodes.iter().any(|node| self.selection.nodes.contains(node.id)) {
+            self.selection.ways.insert(id);
+            if self.strategy != ExtractStrategy::Simple {
+                self.way_nodes.extend(nodes.iter().map(|node| node.id));
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn visit_relation(&mut self, id: i64, members: Vec<MemberReference>, _tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        let selected = members.iter().any(|member| match member.entity_type {
+            OsmEntityType::Node => self.selection.nodes.contains(member.id),
+            OsmEntityType::Way => self.selection.ways.contains(member.id),
+            OsmEntityType::Relation => false,
+        });
+        if selected {
+            self.selection.relations.insert(id);
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// Collects the parent relations of every relation and, for the smart strategy, the member ways of relations of the
+/// complete types. Neither depends on the region, so one collector serves every region of an extract.
+struct RelationCollector<'a> {
+    strategy: ExtractStrategy,
+    complete_relation_types: &'a [String],
+    /// The relations each relation is a member of
+    parent_relations: HashMap<i64, Vec<i64>>,
+    /// The member ways of every relation of a complete type
+    complete_candidates: HashMap<i64, Vec<i64>>,
+}
+
+impl<'a> OsmVisitor for RelationCollector<'a> {
+    fn visit_relation(&mut self, id: i64, members: Vec<MemberReference>, tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        if self.strategy != ExtractStrategy::Simple {
+            for member in members.iter().filter(|member| member.entity_type == OsmEntityType::Relation) {
+                self.parent_relations.entry(member.id).or_insert_with(Vec::new).push(id);
+            }
+        }
+
+        let complete_type = tags.iter().any(|(k, v)| k == "type" && self.complete_relation_types.contains(v));
+        if self.strategy == ExtractStrategy::Smart && complete_type {
+            let ways = members.iter()
+                .filter(|member| member.entity_type == OsmEntityType::Way)
+                .map(|member| member.id)
+                .collect();
+            self.complete_candidates.insert(id, ways);
+        }
+        Ok(Flow::Continue)
+    }
+}
@@ -0,0 +1,362 @@
+use ::PbfParseError;
+use osm::{Entity, OsmEntityType};
+use std::iter::Peekable;
+use std::str::{Chars, FromStr};
+
+/// A predicate on the type and tags of an entity, parsed from a filter expression.
+///
+/// The simplest expressions test a single tag: `highway` matches any entity with a `highway` tag, and
+/// `highway=primary` only those with that value. Keys and values may use `*` as a wildcard, as in `addr:*` or
+/// `name=*street`, a comma separated list matches any of several values, as in `highway=primary,secondary`, and
+/// `!=` matches entities without any of the values. A term can be restricted to some entity types by prefixing
+/// it with any of `n`, `w` and `r` and a slash, as in `w/highway=primary` or `nw/amenity`.
+///
+/// Terms combine with `not` (or `!`), `and` (or `&`) and `or` (or `|`), in decreasing order of precedence, and
+/// can be grouped with parentheses. Keys or values containing spaces or operator characters, or spelled like an
+/// operator keyword, can be quoted.
+#[derive(Debug, Clone)]
+pub struct TagFilter {
+    expression: Expression,
+}
+
+#[derive(Debug, Clone)]
+enum Expression {
+    /// Matches entities of any of the given types
+    Types(Vec<OsmEntityType>),
+    Term { types: Option<Vec<OsmEntityType>>, key: String, values: Option<Vec<String>> },
+    Not(Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+}
+
+impl TagFilter {
+    pub fn parse(text: &str) -> Result<TagFilter, PbfParseError> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens, position: 0 };
+        let expression = parser.parse_or()?;
+        if parser.position < parser.tokens.len() {
+            return Err(filter_error(&format!("unexpected {:?}", parser.tokens[parser.position])));
+        }
+        Ok(TagFilter { expression })
+    }
+
+    pub fn matches(&self, entity_type: OsmEntityType, tags: &[(String, String)]) -> bool {
+        self.expression.matches(entity_type, tags)
+    }
+
+    pub fn matches_entity(&self, entity: &Entity) -> bool {
+        self.matches(entity.entity_type(), entity.tags())
+    }
+}
+
+impl FromStr for TagFilter {
+    type Err = PbfParseError;
+
+    fn from_str(text: &str) -> Result<TagFilter, PbfParseError> {
+        TagFilter::parse(text)
+    }
+}
+
+impl Expression {
+    fn matches(&self, entity_type: OsmEntityType, tags: &[(String, String)]) -> bool {
+        match *self {
+            Expression::Types(ref types) => types.contains(&entity_type),
+            Expression::Term { ref types, ref key, ref values } => {
+                if let Some(ref types) = *types {
+                    if !types.contains(&entity_type) {
+                        return false;
+                    }
+                }
+                tags.iter().any(|(k, v)| {
+                    glob_matches(key, k) && values.as_ref().map_or(true, |values| values.iter().any(|value| glob_matches(value, v)))
+                })
+            }
+            Expression::Not(ref inner) => !inner.matches(entity_type, tags),
+            Expression::And(ref left, ref right) => left.matches(entity_type, tags) && right.matches(entity_type, tags),
+            Expression::Or(ref left, ref right) => left.matches(entity_type, tags) || right.matches(entity_type, tags),
+        }
+    }
+}
+
+/// Matches text against a pattern where `*` stands for any sequence of characters.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    if !pattern.contains('*') {
+        return pattern == text;
+    }
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !text.starts_with(first) || text.len() < first.len() + last.len() || !text[first.len()..].ends_with(last) {
+        return false;
+    }
+    let mut remaining = &text[first.len()..text.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match remaining.find(part) {
+            Some(index) => remaining = &remaining[index + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+fn filter_error(message: &str) -> PbfParseError {
+    PbfParseError::MalformedData(format!("invalid filter expression: {}", message))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    /// A quoted word, which is never treated as an operator keyword
+    Quoted(String),
+    Equals,
+    NotEquals,
+    Not,
+    And,
+    Or,
+    Comma,
+    Slash,
+    Open,
+    Close,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, PbfParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        let token = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '=' => Token::Equals,
+            '!' => {
+                chars.next();
+                if chars.peek() == Some(&'=') {
+                    Token::NotEquals
+                } else {
+                    tokens.push(Token::Not);
+                    continue;
+                }
+            }
+            '&' => Token::And,
+            '|' => Token::Or,
+            ',' => Token::Comma,
+            '/' => Token::Slash,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '"' => {
+                chars.next();
+                tokens.push(Token::Quoted(read_quoted(&mut chars)?));
+                continue;
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "=!&|,/()\"".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(match word.as_str() {
+                    "not" => Token::Not,
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    _ => Token::Word(word),
+                });
+                continue;
+            }
+        };
+        chars.next();
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn read_quoted(chars: &mut Peekable<Chars>) -> Result<String, PbfParseError> {
+    let mut word = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(word),
+            Some('\\') => match chars.next() {
+                Some(c) => word.push(c),
+                None => break,
+            },
+            Some(c) => word.push(c),
+            None => break,
+        }
+    }
+    Err(filter_error("unterminated quote"))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<Expression, PbfParseError> {
+        let mut expression = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expression = Expression::Or(Box::new(expression), Box::new(self.parse_and()?));
+        }
+        Ok(expression)
+    }
+
+    fn parse_and(&mut self) -> Result<Expression, PbfParseError> {
+        let mut expression = self.parse_unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expression = Expression::And(Box::new(expression), Box::new(self.parse_unary()?));
+        }
+        Ok(expression)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression, PbfParseError> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expression::Not(Box::new(self.parse_unary()?))),
+            Some(Token::Open) => {
+                let expression = self.parse_or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expression),
+                    _ => Err(filter_error("missing closing parenthesis")),
+                }
+            }
+            Some(Token::Word(word)) | Some(Token::Quoted(word)) => self.parse_term(word),
+            Some(token) => Err(filter_error(&format!("unexpected {:?}", token))),
+            None => Err(filter_error("unexpected end of expression")),
+        }
+    }
+
+    fn parse_term(&mut self, first: String) -> Result<Expression, PbfParseError> {
+        let (types, key) = if self.peek() == Some(&Token::Slash) {
+            self.next();
+            let types = parse_types(&first)?;
+            match self.next() {
+                Some(Token::Word(key)) | Some(Token::Quoted(key)) => (Some(types), key),
+                _ => return Err(filter_error(&format!("missing key after '{}/'", first))),
+            }
+        } else {
+            (None, first)
+        };
+
+        let negated = match self.peek() {
+            Some(&Token::Equals) => false,
+            Some(&Token::NotEquals) => true,
+            _ => return Ok(Expression::Term { types, key, values: None }),
+        };
+        self.next();
+
+        let mut values = Vec::new();
+        loop {
+            match self.next() {
+                Some(Token::Word(value)) | Some(Token::Quoted(value)) => values.push(value),
+                _ => return Err(filter_error(&format!("missing value for '{}'", key))),
+            }
+            if self.peek() != Some(&Token::Comma) {
+                break;
+            }
+            self.next();
+        }
+
+        if !negated {
+            return Ok(Expression::Term { types, key, values: Some(values) });
+        }
+        // A negated value test still only applies to the given types
+        let negation = Expression::Not(Box::new(Expression::Term { types: None, key, values: Some(values) }));
+        Ok(match types {
+            Some(types) => Expression::And(Box::new(Expression::Types(types)), Box::new(negation)),
+            None => negation,
+        })
+    }
+}
+
+fn parse_types(text: &str) -> Result<Vec<OsmEntityType>, PbfParseError> {
+    text.chars()
+        .map(|c| match c {
+            'n' => Ok(OsmEntityType::Node),
+            'w' => Ok(OsmEntityType::Way),
+            'r' => Ok(OsmEntityType::Relation),
+            _ => Err(filter_error(&format!("unknown entity type '{}'", c))),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn matches(filter: &str, entity_type: OsmEntityType, pairs: &[(&str, &str)]) -> bool {
+        TagFilter::parse(filter).unwrap().matches(entity_type, &tags(pairs))
+    }
+
+    #[test]
+    fn tokenizes_operators_keywords_and_quotes() {
+        let word = |text: &str| Token::Word(text.to_string());
+        assert_eq!(tokenize("w/highway!=primary,secondary").unwrap(),
+                   vec![word("w"), Token::Slash, word("highway"), Token::NotEquals, word("primary"), Token::Comma, word("secondary")]);
+        assert_eq!(tokenize("!(a and b) or c|d & e").unwrap(),
+                   vec![Token::Not, Token::Open, word("a"), Token::And, word("b"), Token::Close, Token::Or, word("c"), Token::Or,
+                        word("d"), Token::And, word("e")]);
+        assert_eq!(tokenize(r#"name="and \"or\"""#).unwrap(),
+                   vec![word("name"), Token::Equals, Token::Quoted("and \"or\"".to_string())]);
+        assert!(tokenize("name=\"open").is_err());
+    }
+
+    #[test]
+    fn matches_keys_values_and_wildcards() {
+        assert!(matches("highway", OsmEntityType::Way, &[("highway", "primary")]));
+        assert!(!matches("highway", OsmEntityType::Way, &[("building", "yes")]));
+        assert!(matches("highway=primary,secondary", OsmEntityType::Way, &[("highway", "secondary")]));
+        assert!(!matches("highway=primary,secondary", OsmEntityType::Way, &[("highway", "residential")]));
+        assert!(matches("addr:*", OsmEntityType::Node, &[("addr:street", "Main Street")]));
+        assert!(matches("name=*street", OsmEntityType::Way, &[("name", "Mainstreet")]));
+        assert!(matches("name=M*n*t", OsmEntityType::Way, &[("name", "Mainstreet")]));
+        assert!(!matches("name=M*x*t", OsmEntityType::Way, &[("name", "Mainstreet")]));
+        assert!(matches(r#""a b"="c d""#, OsmEntityType::Node, &[("a b", "c d")]));
+    }
+
+    #[test]
+    fn restricts_terms_to_entity_types() {
+        assert!(matches("w/highway", OsmEntityType::Way, &[("highway", "primary")]));
+        assert!(!matches("w/highway", OsmEntityType::Node, &[("highway", "primary")]));
+        assert!(matches("nw/amenity", OsmEntityType::Node, &[("amenity", "cafe")]));
+        assert!(!matches("nw/amenity", OsmEntityType::Relation, &[("amenity", "cafe")]));
+        assert!(matches("w/highway!=primary", OsmEntityType::Way, &[("highway", "secondary")]));
+        assert!(matches("w/highway!=primary", OsmEntityType::Way, &[]));
+        assert!(!matches("w/highway!=primary", OsmEntityType::Way, &[("highway", "primary")]));
+        assert!(!matches("w/highway!=primary", OsmEntityType::Node, &[]));
+    }
+
+    #[test]
+    fn applies_operator_precedence() {
+        let tagged = [("a", "1"), ("c", "1")];
+        // `and` binds tighter than `or`, and `not` tighter than `and`
+        assert!(matches("a or b and c", OsmEntityType::Node, &[("a", "1")]));
+        assert!(!matches("(a or b) and c", OsmEntityType::Node, &[("a", "1")]));
+        assert!(matches("not b and a", OsmEntityType::Node, &tagged));
+        assert!(!matches("not (a and c)", OsmEntityType::Node, &tagged));
+        assert!(matches("!b & (c | b)", OsmEntityType::Node, &tagged));
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        for text in &["", "a and", "(a or b", "a)", "x/highway", "w/", "highway=", "highway=a,", "a b"] {
+            assert!(TagFilter::parse(text).is_err(), "{:?} should not parse", text);
+        }
+    }
+}
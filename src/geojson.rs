@@ -0,0 +1,79 @@
+use ::PbfParseError;
+use geometry::Polygon;
+use location::Location;
+use serde_json;
+use std::io::Write;
+
+/// Writes a GeoJSON FeatureCollection one feature at a time.
+pub struct GeoJsonWriter<'a> {
+    writer: &'a mut Write,
+    feature_count: usize,
+}
+
+impl<'a> GeoJsonWriter<'a> {
+    pub fn new(writer: &'a mut Write) -> Result<GeoJsonWriter<'a>, PbfParseError> {
+        writer.write_all(b"{\"type\":\"FeatureCollection\",\"features\":[\n")?;
+        Ok(GeoJsonWriter { writer, feature_count: 0 })
+    }
+
+    pub fn write_point(&mut self, location: Location, properties: &[(&str, String)]) -> Result<(), PbfParseError> {
+        let coordinates = format_location(location);
+        self.write_feature("Point", &coordinates, properties)
+    }
+
+    pub fn write_line(&mut self, locations: &[Location], properties: &[(&str, String)]) -> Result<(), PbfParseError> {
+        let coordinates = format_locations(locations);
+        self.write_feature("LineString", &coordinates, properties)
+    }
+
+    pub fn write_polygon(&mut self, polygon: &Polygon, properties: &[(&str, String)]) -> Result<(), PbfParseError> {
+        let coordinates = format_polygon(polygon);
+        self.write_feature("Polygon", &coordinates, properties)
+    }
+
+    pub fn write_multipolygon(&mut self, polygons: &[Polygon], properties: &[(&str, String)]) -> Result<(), PbfParseError> {
+        let coordinates = format!("[{}]", polygons.iter().map(format_polygon).collect::<Vec<_>>().join(","));
+        self.write_feature("MultiPolygon", &coordinates, properties)
+    }
+
+    pub fn finish(self) -> Result<(), PbfParseError> {
+        self.writer.write_all(b"\n]}\n")?;
+        Ok(())
+    }
+
+    fn write_feature(&mut self, geometry_type: &str, coordinates: &str, properties: &[(&str, String)]) -> Result<(), PbfParseError> {
+        if self.feature_count > 0 {
+            self.writer.write_all(b",\n")?;
+        }
+        let properties: Vec<String> = properties.iter()
+            .map(|(key, value)| {
+                let key = serde_json::to_string(key).expect("strings always serialize");
+                let value = serde_json::to_string(value).expect("strings always serialize");
+                format!("{}:{}", key, value)
+            })
+            .collect();
+        write!(
+            self.writer,
+            "{{\"type\":\"Feature\",\"properties\":{{{}}},\"geometry\":{{\"type\":\"{}\",\"coordinates\":{}}}}}",
+            properties.join(","), geometry_type, coordinates
+        )?;
+        self.feature_count += 1;
+        Ok(())
+    }
+}
+
+fn format_location(location: Location) -> String {
+    format!("[{},{}]", location.longitude, location.latitude)
+}
+
+fn format_locations(locations: &[Location]) -> String {
+    format!("[{}]", locations.iter().map(|l| format_location(*l)).collect::<Vec<_>>().join(","))
+}
+
+fn format_polygon(polygon: &Polygon) -> String {
+    let rings: Vec<String> = Some(&polygon.exterior).into_iter()
+        .chain(polygon.interiors.iter())
+        .map(|ring| format_locations(ring))
+        .collect();
+    format!("[{}]", rings.join(","))
+}
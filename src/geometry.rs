@@ -0,0 +1,408 @@
+use location::Location;
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+
+/// A polygon in longitude/latitude space, with its exterior ring counter-clockwise and its interior rings
+/// clockwise. Rings are closed, repeating their first location at the end.
+#[derive(Debug, Clone)]
+pub struct Polygon {
+    pub exterior: Vec<Location>,
+    pub interiors: Vec<Vec<Location>>,
+}
+
+impl Polygon {
+    pub fn new(mut exterior: Vec<Location>, mut interiors: Vec<Vec<Location>>) -> Polygon {
+        orient(&mut exterior, true);
+        for interior in interiors.iter_mut() {
+            orient(interior, false);
+        }
+        Polygon { exterior, interiors }
+    }
+
+    pub fn area(&self) -> f64 {
+        signed_area(&self.exterior).abs() - self.interiors.iter().map(|ring| signed_area(ring).abs()).sum::<f64>()
+    }
+
+    pub fn contains(&self, location: Location) -> bool {
+        ring_contains(&self.exterior, location) && !self.interiors.iter().any(|ring| ring_contains(ring, location))
+    }
+}
+
+/// An axis-aligned rectangle in longitude/latitude space, with edges named as in the PBF file header.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BoundingBox {
+    pub left: f64,
+    pub bottom: f64,
+    pub right: f64,
+    pub top: f64,
+}
+
+impl BoundingBox {
+    pub fn new(left: f64, bottom: f64, right: f64, top: f64) -> BoundingBox {
+        BoundingBox { left, bottom, right, top }
+    }
+
+    /// The smallest box containing all of the given locations, or `None` if there are none.
+    pub fn from_locations(locations: &[Location]) -> Option<BoundingBox> {
+        let first = match locations.first() {
+            Some(first) => *first,
+            None => return None,
+        };
+        let mut bbox = BoundingBox::new(first.longitude, first.latitude, first.longitude, first.latitude);
+        for location in &locations[1..] {
+            bbox.expand(*location);
+        }
+        Some(bbox)
+    }
+
+    pub fn expand(&mut self, location: Location) {
+        self.left = self.left.min(location.longitude);
+        self.bottom = self.bottom.min(location.latitude);
+        self.right = self.right.max(location.longitude);
+        self.top = self.top.max(location.latitude);
+    }
+
+    pub fn contains(&self, location: Location) -> bool {
+        location.longitude >= self.left && location.longitude <= self.right
+            && location.latitude >= self.bottom && location.latitude <= self.top
+    }
+
+    pub fn intersects(&self, other: &BoundingBox) -> bool {
+        self.left <= other.right && other.left <= self.right && self.bottom <= other.top && other.bottom <= self.top
+    }
+}
+
+/// A sequence of connected ways, as joined by `join_chains`.
+#[derive(Debug, Clone)]
+pub struct Chain {
+    /// The IDs of the ways making up this chain in order, along with whether each was reversed to fit
+    pub ways: Vec<(i64, bool)>,
+    pub nodes: Vec<(i64, Location)>,
+}
+
+impl Chain {
+    pub fn from_way(id: i64, nodes: Vec<(i64, Location)>) -> Chain {
+        Chain { ways: vec![(id, false)], nodes }
+    }
+
+    pub fn first_node(&self) -> i64 {
+        self.nodes[0].0
+    }
+
+    pub fn last_node(&self) -> i64 {
+        self.nodes[self.nodes.len() - 1].0
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.nodes.len() > 3 && self.first_node() == self.last_node()
+    }
+
+    pub fn locations(&self) -> Vec<Location> {
+        self.nodes.iter().map(|node| node.1).collect()
+    }
+
+    pub fn way_ids(&self) -> Vec<i64> {
+        self.ways.iter().map(|way| way.0).collect()
+    }
+
+    fn reversed(&self) -> Chain {
+        Chain {
+            ways: self.ways.iter().rev().map(|&(id, reversed)| (id, !reversed)).collect(),
+            nodes: self.nodes.iter().rev().cloned().collect(),
+        }
+    }
+}
+
+/// Joins chains end to start wherever they share an end node, until they close into rings or can't be extended.
+/// When `allow_reverse` is set, chains may also be reversed to fit, such as for multipolygon members where way
+/// direction has no meaning. Chains with fewer than two nodes are dropped.
+pub fn join_chains(chains: Vec<Chain>, allow_reverse: bool) -> Vec<Chain> {
+    let chains: Vec<Chain> = chains.into_iter().filter(|chain| chain.nodes.len() >= 2).collect();
+
+    let mut starts: HashMap<i64, Vec<usize>> = HashMap::new();
+    let mut ends: HashMap<i64, Vec<usize>> = HashMap::new();
+    for (index, chain) in chains.iter().enumerate() {
+        starts.entry(chain.first_node()).or_insert_with(Vec::new).push(index);
+        ends.entry(chain.last_node()).or_insert_with(Vec::new).push(index);
+    }
+
+    let mut used = vec![false; chains.len()];
+    let mut joined = Vec::new();
+
+    for index in 0..chains.len() {
+        if used[index] {
+            continue;
+        }
+        used[index] = true;
+
+        let mut pieces: VecDeque<(usize, bool)> = VecDeque::new();
+        pieces.push_back((index, false));
+        let first = chains[index].first_node();
+        let mut head = first;
+        let mut tail = chains[index].last_node();
+
+        // Extend forwards from the tail
+        while tail != first {
+            match find_unused(&starts, &ends, &used, tail, allow_reverse) {
+                Some((next, reversed)) => {
+                    used[next] = true;
+                    pieces.push_back((next, reversed));
+                    tail = if reversed { chains[next].first_node() } else { chains[next].last_node() };
+                }
+                None => break,
+            }
+        }
+
+        // Extend backwards from the head if the chain didn't close
+        while tail != head {
+            match find_unused(&ends, &starts, &used, head, allow_reverse) {
+                Some((previous, reversed)) => {
+                    used[previous] = true;
+                    pieces.push_front((previous, reversed));
+                    head = if reversed { chains[previous].last_node() } else { chains[previous].first_node() };
+                }
+                None => break,
+            }
+        }
+
+        let mut chain = Chain { ways: Vec::new(), nodes: Vec::new() };
+        for (piece, reversed) in pieces {
+            let piece = if reversed { chains[piece].reversed() } else { chains[piece].clone() };
+            // Consecutive pieces share their connecting node
+            let skip = if chain.nodes.is_empty() { 0 } else { 1 };
+            chain.nodes.extend(piece.nodes.into_iter().skip(skip));
+            chain.ways.extend(piece.ways);
+        }
+        joined.push(chain);
+    }
+
+    joined
+}
+
+/// Finds an unused chain that continues from `node`: one that starts there as-is, or when reversing is allowed,
+/// one that ends there.
+fn find_unused(forward: &HashMap<i64, Vec<usize>>, backward: &HashMap<i64, Vec<usize>>, used: &[bool], node: i64, allow_reverse: bool) -> Option<(usize, bool)> {
+    if let Some(index) = forward.get(&node).and_then(|indices| indices.iter().find(|i| !used[**i])) {
+        return Some((*index, false));
+    }
+    if allow_reverse {
+        if let Some(index) = backward.get(&node).and_then(|indices| indices.iter().find(|i| !used[**i])) {
+            return Some((*index, true));
+        }
+    }
+    None
+}
+
+/// The signed area of a closed ring in square degrees, positive when counter-clockwise.
+pub fn signed_area(ring: &[Location]) -> f64 {
+    let mut area = 0.0;
+    for window in ring.windows(2) {
+        area += window[0].longitude * window[1].latitude - window[1].longitude * window[0].latitude;
+    }
+    area / 2.0
+}
+
+/// Reverses a closed ring if needed so that it is counter-clockwise, or clockwise if `counter_clockwise` is unset.
+pub fn orient(ring: &mut Vec<Location>, counter_clockwise: bool) {
+    if (signed_area(ring) > 0.0) != counter_clockwise {
+        ring.reverse();
+    }
+}
+
+/// Tests whether a location lies inside a closed ring using the even-odd rule.
+pub fn ring_contains(ring: &[Location], location: Location) -> bool {
+    let mut inside = false;
+    for window in ring.windows(2) {
+        let (a, b) = (window[0], window[1]);
+        if (a.latitude > location.latitude) != (b.latitude > location.latitude) {
+            let crossing = a.longitude + (location.latitude - a.latitude) / (b.latitude - a.latitude) * (b.longitude - a.longitude);
+            if location.longitude < crossing {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+/// Finds a location where two non-adjacent segments of a closed ring intersect, if any. Segments are swept in
+/// order of their minimum longitude, so only segments with overlapping extents are compared.
+pub fn find_self_intersection(ring: &[Location]) -> Option<Location> {
+    let segment_count = ring.len().saturating_sub(1);
+    let mut segments: Vec<usize> = (0..segment_count).collect();
+    let min_lon = |i: usize| ring[i].longitude.min(ring[i + 1].longitude);
+    let max_lon = |i: usize| ring[i].longitude.max(ring[i + 1].longitude);
+    segments.sort_by(|a, b| min_lon(*a).partial_cmp(&min_lon(*b)).unwrap_or(Ordering::Equal));
+
+    for (position, &a) in segments.iter().enumerate() {
+        for &b in &segments[position + 1..] {
+            if min_lon(b) > max_lon(a) {
+                break;
+            }
+            let adjacent = a + 1 == b || b + 1 == a || (a.min(b) == 0 && a.max(b) == segment_count - 1);
+            if adjacent {
+                continue;
+            }
+            if let Some(location) = segment_intersection(ring[a], ring[a + 1], ring[b], ring[b + 1]) {
+                return Some(location);
+            }
+        }
+    }
+    None
+}
+
+/// Finds a location where segments `a1-a2` and `b1-b2` touch, including collinear overlaps.
+pub fn segment_intersection(a1: Location, a2: Location, b1: Location, b2: Location) -> Option<Location> {
+    let d1 = cross(b1, b2, a1);
+    let d2 = cross(b1, b2, a2);
+    let d3 = cross(a1, a2, b1);
+    let d4 = cross(a1, a2, b2);
+
+    if ((d1 > 0.0 && d2 < 0.0) || (d1 < 0.0 && d2 > 0.0)) && ((d3 > 0.0 && d4 < 0.0) || (d3 < 0.0 && d4 > 0.0)) {
+        let t = d1 / (d1 - d2);
+        return Some(Location::new(
+            a1.latitude + t * (a2.latitude - a1.latitude),
+            a1.longitude + t * (a2.longitude - a1.longitude),
+        ));
+    }
+
+    if d1 == 0.0 && on_segment(b1, b2, a1) {
+        Some(a1)
+    } else if d2 == 0.0 && on_segment(b1, b2, a2) {
+        Some(a2)
+    } else if d3 == 0.0 && on_segment(a1, a2, b1) {
+        Some(b1)
+    } else if d4 == 0.0 && on_segment(a1, a2, b2) {
+        Some(b2)
+    } else {
+        None
+    }
+}
+
+fn cross(origin: Location, a: Location, b: Location) -> f64 {
+    (a.longitude - origin.longitude) * (b.latitude - origin.latitude) - (a.latitude - origin.latitude) * (b.longitude - origin.longitude)
+}
+
+fn on_segment(a: Location, b: Location, point: Location) -> bool {
+    point.longitude >= a.longitude.min(b.longitude) && point.longitude <= a.longitude.max(b.longitude)
+        && point.latitude >= a.latitude.min(b.latitude) && point.latitude <= a.latitude.max(b.latitude)
+}
+
+/// Clips a closed ring to a bounding box with the Sutherland-Hodgman algorithm, returning an empty ring if nothing
+/// is left. Where a concave ring leaves and re-enters the box, the pieces stay connected along the box edge by
+/// zero-width spikes rather than being split into separate rings.
+pub fn clip_ring(ring: &[Location], bbox: &BoundingBox) -> Vec<Location> {
+    let mut output: Vec<Location> = ring[..ring.len().saturating_sub(1)].to_vec();
+    for edge in 0..4 {
+        let input = mem::replace(&mut output, Vec::new());
+        if input.is_empty() {
+            break;
+        }
+        let inside = |location: Location| match edge {
+            0 => location.longitude >= bbox.left,
+            1 => location.longitude <= bbox.right,
+            2 => location.latitude >= bbox.bottom,
+            _ => location.latitude <= bbox.top,
+        };
+        let intersect = |a: Location, b: Location| match edge {
+            0 | 1 => {
+                let longitude = if edge == 0 { bbox.left } else { bbox.right };
+                let t = (longitude - a.longitude) / (b.longitude - a.longitude);
+                Location::new(a.latitude + t * (b.latitude - a.latitude), longitude)
+            }
+            _ => {
+                let latitude = if edge == 2 { bbox.bottom } else { bbox.top };
+                let t = (latitude - a.latitude) / (b.latitude - a.latitude);
+                Location::new(latitude, a.longitude + t * (b.longitude - a.longitude))
+            }
+        };
+
+        let mut previous = input[input.len() - 1];
+        for &current in &input {
+            if inside(current) {
+                if !inside(previous) {
+                    output.push(intersect(previous, current));
+                }
+                output.push(current);
+            } else if inside(previous) {
+                output.push(intersect(previous, current));
+            }
+            previous = current;
+        }
+    }
+
+    if output.len() < 3 {
+        return Vec::new();
+    }
+    let first = output[0];
+    output.push(first);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn way(id: i64, node_ids: &[i64]) -> Chain {
+        Chain::from_way(id, node_ids.iter().map(|&node| (node, Location::new(node as f64, 0.0))).collect())
+    }
+
+    fn node_ids(chain: &Chain) -> Vec<i64> {
+        chain.nodes.iter().map(|node| node.0).collect()
+    }
+
+    #[test]
+    fn joins_ways_into_a_ring() {
+        let joined = join_chains(vec![way(1, &[1, 2, 3]), way(2, &[3, 4]), way(3, &[4, 1])], false);
+        assert_eq!(joined.len(), 1);
+        assert_eq!(node_ids(&joined[0]), vec![1, 2, 3, 4, 1]);
+        assert_eq!(joined[0].ways, vec![(1, false), (2, false), (3, false)]);
+        assert!(joined[0].is_closed());
+    }
+
+    #[test]
+    fn extends_chains_backwards_from_their_head() {
+        let joined = join_chains(vec![way(2, &[3, 4]), way(1, &[1, 2, 3])], false);
+        assert_eq!(joined.len(), 1);
+        assert_eq!(node_ids(&joined[0]), vec![1, 2, 3, 4]);
+        assert_eq!(joined[0].way_ids(), vec![1, 2]);
+        assert!(!joined[0].is_closed());
+    }
+
+    #[test]
+    fn reverses_ways_only_when_allowed() {
+        let chains = vec![way(1, &[1, 2, 3]), way(2, &[1, 4, 3])];
+        let joined = join_chains(chains.clone(), true);
+        assert_eq!(joined.len(), 1);
+        assert_eq!(node_ids(&joined[0]), vec![1, 2, 3, 4, 1]);
+        assert_eq!(joined[0].ways, vec![(1, false), (2, true)]);
+
+        assert_eq!(join_chains(chains, false).len(), 2);
+    }
+
+    #[test]
+    fn drops_chains_with_fewer_than_two_nodes() {
+        let joined = join_chains(vec![way(1, &[1]), way(2, &[]), way(3, &[1, 2])], false);
+        assert_eq!(joined.len(), 1);
+        assert_eq!(joined[0].way_ids(), vec![3]);
+    }
+
+    #[test]
+    fn tests_containment_with_the_even_odd_rule() {
+        // A square with a notch cut into its top edge
+        let ring: Vec<Location> = [(0.0, 0.0), (0.0, 4.0), (4.0, 4.0), (4.0, 3.0), (2.0, 3.0), (2.0, 1.0), (4.0, 1.0), (4.0, 0.0), (0.0, 0.0)]
+            .iter()
+            .map(|&(longitude, latitude)| Location::new(latitude, longitude))
+            .collect();
+        assert!(ring_contains(&ring, Location::new(1.0, 1.0)));
+        assert!(ring_contains(&ring, Location::new(3.5, 3.0)));
+        assert!(!ring_contains(&ring, Location::new(2.0, 3.0)));
+        assert!(!ring_contains(&ring, Location::new(2.0, 5.0)));
+        assert!(!ring_contains(&ring, Location::new(-1.0, 1.0)));
+
+        let mut reversed = ring.clone();
+        reversed.reverse();
+        assert!(ring_contains(&reversed, Location::new(1.0, 1.0)));
+        assert!(!ring_contains(&reversed, Location::new(2.0, 3.0)));
+    }
+}
@@ -0,0 +1,426 @@
+use std::iter::FromIterator;
+use std::slice;
+
+const CHUNK_BITS: u32 = 16;
+const CHUNK_MASK: i64 = (1 << CHUNK_BITS) - 1;
+const CHUNK_WORDS: usize = (1 << CHUNK_BITS) / 64;
+/// Chunks holding more IDs than this are stored as bitmaps, which are smaller at this point
+const MAX_ARRAY_LENGTH: usize = CHUNK_WORDS * 4;
+
+/// A compact set of entity IDs. IDs are grouped into chunks of 65536 consecutive values, each stored either as a
+/// sorted array when sparse or as a bitmap when dense, so that dense selections cost around one bit per ID.
+/// Inserting in ascending order, as IDs appear in a sorted file, is the fast path.
+#[derive(Debug, Clone, Default)]
+pub struct IdSet {
+    chunks: Vec<(i64, Chunk)>,
+    len: usize,
+}
+
+#[derive(Debug, Clone)]
+enum Chunk {
+    Array(Vec<u16>),
+    Bitmap(Box<[u64]>),
+}
+
+impl IdSet {
+    pub fn new() -> IdSet {
+        IdSet { chunks: Vec::new(), len: 0 }
+    }
+
+    /// Inserts the given ID, returning whether it was not already present.
+    pub fn insert(&mut self, id: i64) -> bool {
+        let (key, low) = split_id(id);
+        let index = match self.find_chunk(key) {
+            Ok(index) => index,
+            Err(index) => {
+                self.chunks.insert(index, (key, Chunk::Array(Vec::new())));
+                index
+            }
+        };
+        let inserted = self.chunks[index].1.insert(low);
+        if inserted {
+            self.len += 1;
+        }
+        inserted
+    }
+
+    pub fn contains(&self, id: i64) -> bool {
+        let (key, low) = split_id(id);
+        match self.find_chunk(key) {
+            Ok(index) => self.chunks[index].1.contains(low),
+            Err(_) => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterates over all IDs in ascending order.
+    pub fn iter(&self) -> Iter {
+        Iter { chunks: self.chunks.iter(), current: None }
+    }
+
+    /// Adds every ID from the other set to this one.
+    pub fn union(&mut self, other: &IdSet) {
+        for &(key, ref other_chunk) in &other.chunks {
+            let index = match self.find_chunk(key) {
+                Ok(index) => index,
+                Err(index) => {
+                    self.chunks.insert(index, (key, other_chunk.clone()));
+                    self.len += other_chunk.len();
+                    continue;
+                }
+            };
+            let chunk = &mut self.chunks[index].1;
+            let before = chunk.len();
+            chunk.union(other_chunk);
+            self.len += chunk.len() - before;
+        }
+    }
+
+    fn find_chunk(&self, key: i64) -> Result<usize, usize> {
+        // Check the last chunk first, since IDs are usually inserted and queried in ascending order
+        match self.chunks.last() {
+            Some(&(last, _)) if last == key => Ok(self.chunks.len() - 1),
+            Some(&(last, _)) if last < key => Err(self.chunks.len()),
+            None => Err(0),
+            _ => self.chunks.binary_search_by_key(&key, |&(key, _)| key),
+        }
+    }
+}
+
+impl Chunk {
+    fn insert(&mut self, low: u16) -> bool {
+        let inserted = match *self {
+            Chunk::Array(ref mut values) => {
+                if values.last().map_or(true, |last| *last < low) {
+                    values.push(low);
+                    true
+                } else {
+                    match values.binary_search(&low) {
+                        Ok(_) => false,
+                        Err(index) => {
+                            values.insert(index, low);
+                            true
+                        }
+                    }
+                }
+            }
+            Chunk::Bitmap(ref mut words) => {
+                let (word, bit) = (low as usize / 64, low as usize % 64);
+                let present = words[word] & (1 << bit) != 0;
+                words[word] |= 1 << bit;
+                !present
+            }
+        };
+        if inserted {
+            self.promote_if_needed();
+        }
+        inserted
+    }
+
+    fn contains(&self, low: u16) -> bool {
+        match *self {
+            Chunk::Array(ref values) => values.binary_search(&low).is_ok(),
+            Chunk::Bitmap(ref words) => words[low as usize / 64] & (1 << (low as usize % 64)) != 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match *self {
+            Chunk::Array(ref values) => values.len(),
+            Chunk::Bitmap(ref words) => words.iter().map(|word| word.count_ones() as usize).sum(),
+        }
+    }
+
+    fn union(&mut self, other: &Chunk) {
+        match (self as &mut Chunk, other) {
+            (&mut Chunk::Bitmap(ref mut words), &Chunk::Bitmap(ref other_words)) => {
+                for (word, other_word) in words.iter_mut().zip(other_words.iter()) {
+                    *word |= *other_word;
+                }
+                return;
+            }
+            _ => (),
+        }
+        for low in other.iter() {
+            self.insert(low);
+        }
+    }
+
+    fn iter(&self) -> ChunkIter {
+        match *self {
+            Chunk::Array(ref values) => ChunkIter::Array(values.iter()),
+            Chunk::Bitmap(ref words) => ChunkIter::Bitmap { words, index: 0, word: words[0] },
+        }
+    }
+
+    fn promote_if_needed(&mut self) {
+        let words = match *self {
+            Chunk::Array(ref values) if values.len() > MAX_ARRAY_LENGTH => {
+                let mut words = vec![0u64; CHUNK_WORDS].into_boxed_slice();
+                for low in values {
+                    words[*low as usize / 64] |= 1 << (*low as usize % 64);
+                }
+                words
+            }
+            _ => return,
+        };
+        *self = Chunk::Bitmap(words);
+    }
+}
+
+/// Numbers the IDs of a set by their position in ascending order, such as to renumber them densely.
+#[derive(Debug, Clone)]
+pub struct IdIndex {
+    set: IdSet,
+    /// The number of IDs in the chunks before each chunk
+    chunk_offsets: Vec<usize>,
+    /// The number of IDs before each word of each bitmap chunk, so that positions don't need a count over the
+    /// whole bitmap
+    word_offsets: Vec<Option<Box<[u16]>>>,
+}
+
+impl IdIndex {
+    pub fn new(set: IdSet) -> IdIndex {
+        let mut chunk_offsets = Vec::with_capacity(set.chunks.len());
+        let mut word_offsets = Vec::with_capacity(set.chunks.len());
+        let mut offset = 0;
+        for &(_, ref chunk) in &set.chunks {
+            chunk_offsets.push(offset);
+            offset += chunk.len();
+            word_offsets.push(match *chunk {
+                Chunk::Array(_) => None,
+                Chunk::Bitmap(ref words) => {
+                    let mut count = 0;
+                    let offsets: Vec<u16> = words.iter()
+                        .map(|word| {
+                            let before = count as u16;
+                            count += word.count_ones();
+                            before
+                        })
+                        .collect();
+                    Some(offsets.into_boxed_slice())
+                }
+            });
+        }
+        IdIndex { set, chunk_offsets, word_offsets }
+    }
+
+    /// The number of IDs in the set lower than the given one, if it is in the set.
+    pub fn position(&self, id: i64) -> Option<usize> {
+        let (key, low) = split_id(id);
+        let index = self.set.find_chunk(key).ok()?;
+        let within = match (&self.set.chunks[index].1, &self.word_offsets[index]) {
+            (&Chunk::Array(ref values), _) => values.binary_search(&low).ok()?,
+            (&Chunk::Bitmap(ref words), &Some(ref offsets)) => {
+                let (word, bit) = (low as usize / 64, low as usize % 64);
+                if words[word] & (1 << bit) == 0 {
+                    return None;
+                }
+                offsets[word] as usize + (words[word] & ((1 << bit) - 1)).count_ones() as usize
+            }
+            (&Chunk::Bitmap(_), &None) => unreachable!("bitmap chunks have word offsets"),
+        };
+        Some(self.chunk_offsets[index] + within)
+    }
+
+    pub fn len(&self) -> usize {
+        self.set.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+    }
+
+    pub fn set(&self) -> &IdSet {
+        &self.set
+    }
+}
+
+fn split_id(id: i64) -> (i64, u16) {
+    (id >> CHUNK_BITS, (id & CHUNK_MASK) as u16)
+}
+
+pub struct Iter<'a> {
+    chunks: slice::Iter<'a, (i64, Chunk)>,
+    current: Option<(i64, ChunkIter<'a>)>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = i64;
+
+    fn next(&mut self) -> Option<i64> {
+        loop {
+            if let Some((key, ref mut chunk)) = self.current {
+                if let Some(low) = chunk.next() {
+                    return Some((key << CHUNK_BITS) | low as i64);
+                }
+            }
+            match self.chunks.next() {
+                Some(&(key, ref chunk)) => self.current = Some((key, chunk.iter())),
+                None => return None,
+            }
+        }
+    }
+}
+
+enum ChunkIter<'a> {
+    Array(slice::Iter<'a, u16>),
+    Bitmap { words: &'a [u64], index: usize, word: u64 },
+}
+
+impl<'a> Iterator for ChunkIter<'a> {
+    type Item = u16;
+
+    fn next(&mut self) -> Option<u16> {
+        match *self {
+            ChunkIter::Array(ref mut values) => values.next().cloned(),
+            ChunkIter::Bitmap { words, ref mut index, ref mut word } => {
+                while *word == 0 {
+                    *index += 1;
+                    if *index >= words.len() {
+                        return None;
+                    }
+                    *word = words[*index];
+                }
+                let bit = word.trailing_zeros() as usize;
+                *word &= *word - 1;
+                Some((*index * 64 + bit) as u16)
+            }
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a IdSet {
+    type Item = i64;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+impl Extend<i64> for IdSet {
+    fn extend<I: IntoIterator<Item = i64>>(&mut self, ids: I) {
+        for id in ids {
+            self.insert(id);
+        }
+    }
+}
+
+impl FromIterator<i64> for IdSet {
+    fn from_iter<I: IntoIterator<Item = i64>>(ids: I) -> IdSet {
+        let mut set = IdSet::new();
+        set.extend(ids);
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_bitmap(set: &IdSet, key: i64) -> bool {
+        match set.chunks[set.find_chunk(key).unwrap()].1 {
+            Chunk::Array(_) => false,
+            Chunk::Bitmap(_) => true,
+        }
+    }
+
+    #[test]
+    fn promotes_chunks_to_bitmaps_beyond_the_array_length() {
+        let mut set: IdSet = (0..MAX_ARRAY_LENGTH as i64).map(|id| id * 2).collect();
+        assert!(!is_bitmap(&set, 0));
+        assert!(set.insert(1));
+        assert!(is_bitmap(&set, 0));
+        assert!(!set.insert(1));
+
+        assert_eq!(set.len(), MAX_ARRAY_LENGTH + 1);
+        assert!(set.contains(0) && set.contains(1) && set.contains(2));
+        assert!(!set.contains(3));
+        let mut expected: Vec<i64> = (0..MAX_ARRAY_LENGTH as i64).map(|id| id * 2).collect();
+        expected.insert(1, 1);
+        assert_eq!(set.iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn inserts_out_of_order_and_iterates_in_ascending_order() {
+        let mut set = IdSet::new();
+        for &id in &[70_000, 5, 1 << 40, 3, 70_000, 65_535] {
+            set.insert(id);
+        }
+        assert_eq!(set.len(), 5);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![3, 5, 65_535, 70_000, 1 << 40]);
+    }
+
+    #[test]
+    fn orders_negative_ids_before_positive_ones() {
+        let set: IdSet = vec![1, -1, -65_537, 0, -65_536, i64::min_value(), i64::max_value()].into_iter().collect();
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![i64::min_value(), -65_537, -65_536, -1, 0, 1, i64::max_value()]);
+        assert!(set.contains(-1));
+        assert!(!set.contains(-2));
+    }
+
+    #[test]
+    fn unions_array_and_bitmap_chunks() {
+        let dense: Vec<i64> = (0..MAX_ARRAY_LENGTH as i64 + 10).collect();
+        let sparse = [5, MAX_ARRAY_LENGTH as i64 + 20, 100_000, -3];
+
+        // Bitmap into array, array into bitmap, bitmap into bitmap, and a chunk missing from the target
+        let mut from_sparse: IdSet = sparse.iter().cloned().collect();
+        from_sparse.union(&dense.iter().cloned().collect());
+        let mut from_dense: IdSet = dense.iter().cloned().collect();
+        from_dense.union(&sparse.iter().cloned().collect());
+        let mut both_dense: IdSet = dense.iter().map(|id| id + 100).collect();
+        both_dense.union(&dense.iter().cloned().collect());
+
+        let mut expected: Vec<i64> = dense.iter().chain(sparse.iter()).cloned().collect();
+        expected.sort();
+        expected.dedup();
+        for set in &[from_sparse, from_dense] {
+            assert_eq!(set.len(), expected.len());
+            assert_eq!(set.iter().collect::<Vec<_>>(), expected);
+            assert!(is_bitmap(set, 0));
+        }
+        assert_eq!(both_dense.len(), dense.len() + 100);
+        assert_eq!(both_dense.iter().collect::<Vec<_>>(), (0..MAX_ARRAY_LENGTH as i64 + 110).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn positions_ids_present_in_the_index() {
+        // An array chunk of negative IDs, a bitmap chunk and another array chunk
+        let mut ids: Vec<i64> = vec![-70_000, -5];
+        ids.extend((0..MAX_ARRAY_LENGTH as i64 + 1).map(|id| id * 3));
+        ids.extend(&[1 << 20, (1 << 20) + 7]);
+        let index = IdIndex::new(ids.iter().cloned().collect());
+        assert!(is_bitmap(index.set(), 0));
+        assert_eq!(index.len(), ids.len());
+        for (position, &id) in ids.iter().enumerate() {
+            assert_eq!(index.position(id), Some(position), "position of {}", id);
+        }
+    }
+
+    #[test]
+    fn positions_nothing_for_absent_ids() {
+        let mut ids: Vec<i64> = (0..MAX_ARRAY_LENGTH as i64 + 1).map(|id| id * 3).collect();
+        ids.push(1 << 20);
+        let index = IdIndex::new(ids.into_iter().collect());
+        for &id in &[1, 2, 64, 65_535, 1 << 17, (1 << 20) + 1, -1] {
+            assert_eq!(index.position(id), None, "position of {}", id);
+        }
+        assert_eq!(IdIndex::new(IdSet::new()).position(0), None);
+    }
+
+    #[test]
+    fn positions_ids_in_a_full_bitmap_chunk() {
+        let index = IdIndex::new((0..1 << CHUNK_BITS).chain(vec![1 << CHUNK_BITS]).collect());
+        assert_eq!(index.position(65_535), Some(65_535));
+        assert_eq!(index.position(1 << CHUNK_BITS), Some(1 << CHUNK_BITS));
+    }
+}
@@ -0,0 +1,207 @@
+use ::PbfParseError;
+use blob::{Blob, BlobLayout, BlobType};
+use osm::OsmEntityType;
+use protos::osm::{HeaderBlock, PrimitiveBlock, PrimitiveGroup};
+use reader::{BlobReader, ErrorSummary};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Seek};
+use visitor::{BlobVisitor, Flow, VisitResult};
+
+/// The number of entities of one type and the lowest and highest of their IDs.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct IdRange {
+    pub count: usize,
+    pub min: i64,
+    pub max: i64,
+}
+
+impl IdRange {
+    fn add(&mut self, id: i64) {
+        self.count += 1;
+        self.min = self.min.min(id);
+        self.max = self.max.max(id);
+    }
+
+    fn merge(&mut self, other: &IdRange) {
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+}
+
+fn add_id(ranges: &mut BTreeMap<OsmEntityType, IdRange>, entity_type: OsmEntityType, id: i64) {
+    ranges.entry(entity_type).or_insert(IdRange { count: 0, min: id, max: id }).add(id);
+}
+
+/// The encoding parameters and contents of a primitive block.
+#[derive(Debug, Clone)]
+pub struct BlockSummary {
+    pub granularity: i32,
+    pub date_granularity: i32,
+    pub lat_offset: i64,
+    pub lon_offset: i64,
+    pub strings: usize,
+    pub groups: usize,
+    pub entities: BTreeMap<OsmEntityType, IdRange>,
+}
+
+/// The position, storage and, for data blobs, block contents of a single blob.
+#[derive(Debug, Clone)]
+pub struct BlobSummary {
+    pub index: usize,
+    pub offset: u64,
+    pub data_type: BlobType,
+    pub layout: BlobLayout,
+    /// The size of the blob's data once decompressed
+    pub raw_size: usize,
+    pub block: Option<BlockSummary>,
+}
+
+/// The structure of a file, as reported by the `info` command.
+///
+/// Blocks are decoded only as far as needed to count entities and read their IDs, without resolving tags or
+/// coordinates, so this is much faster than a full pass with an `OsmReader`.
+#[derive(Debug, Clone)]
+pub struct FileInfo {
+    /// The first header block in the file
+    pub header: Option<HeaderBlock>,
+    pub blobs: Vec<BlobSummary>,
+    pub entities: BTreeMap<OsmEntityType, IdRange>,
+    /// Whether all entities appear in order of type and then strictly increasing ID, whatever the header claims
+    pub sorted: bool,
+}
+
+impl FileInfo {
+    pub fn read<T: Read + Seek>(reader: &mut BlobReader<T>) -> Result<(FileInfo, ErrorSummary), PbfParseError> {
+        let mut visitor = InfoVisitor {
+            info: FileInfo { header: None, blobs: Vec::new(), entities: BTreeMap::new(), sorted: true },
+            index: 0,
+            offset: 0,
+            last: None,
+        };
+        let summary = reader.accept(&mut visitor)?;
+        Ok((visitor.info, summary))
+    }
+
+    /// Whether the header declares the file as sorted by type and then ID.
+    pub fn declared_sorted(&self) -> bool {
+        self.header.as_ref().map_or(false, |header| {
+            header.get_optional_features().iter().any(|feature| feature == "Sort.Type_then_ID")
+        })
+    }
+
+    pub fn stored_size(&self) -> u64 {
+        self.blobs.iter().map(|blob| blob.layout.stored_size()).sum()
+    }
+
+    pub fn raw_size(&self) -> u64 {
+        self.blobs.iter().map(|blob| blob.raw_size as u64).sum()
+    }
+
+    /// The number of blobs stored with each compression codec, by codec name.
+    pub fn compressions(&self) -> BTreeMap<&'static str, usize> {
+        let mut compressions = BTreeMap::new();
+        for blob in &self.blobs {
+            *compressions.entry(blob.layout.compression.name()).or_insert(0) += 1;
+        }
+        compressions
+    }
+
+    pub fn granularities(&self) -> BTreeSet<i32> {
+        self.blocks().map(|block| block.granularity).collect()
+    }
+
+    pub fn date_granularities(&self) -> BTreeSet<i32> {
+        self.blocks().map(|block| block.date_granularity).collect()
+    }
+
+    /// The distinct latitude and longitude offsets of the blocks.
+    pub fn offsets(&self) -> BTreeSet<(i64, i64)> {
+        self.blocks().map(|block| (block.lat_offset, block.lon_offset)).collect()
+    }
+
+    fn blocks<'a>(&'a self) -> Box<Iterator<Item = &'a BlockSummary> + 'a> {
+        Box::new(self.blobs.iter().filter_map(|blob| blob.block.as_ref()))
+    }
+}
+
+struct InfoVisitor {
+    info: FileInfo,
+    /// The index and byte offset of the next blob, which are exact since blobs are read without resynchronizing
+    index: usize,
+    offset: u64,
+    last: Option<(OsmEntityType, i64)>,
+}
+
+impl InfoVisitor {
+    fn summarize_block(&mut self, block: &PrimitiveBlock) -> BlockSummary {
+        let mut entities = BTreeMap::new();
+        for group in block.get_primitivegroup() {
+            for (entity_type, id) in group_ids(group) {
+                add_id(&mut entities, entity_type, id);
+                if self.last.map_or(false, |last| last >= (entity_type, id)) {
+                    self.info.sorted = false;
+                }
+                self.last = Some((entity_type, id));
+            }
+        }
+        BlockSummary {
+            granularity: block.get_granularity(),
+            date_granularity: block.get_date_granularity(),
+            lat_offset: block.get_lat_offset(),
+            lon_offset: block.get_lon_offset(),
+            strings: block.get_stringtable().get_s().len(),
+            groups: block.get_primitivegroup().len(),
+            entities,
+        }
+    }
+}
+
+/// The type and ID of every entity in a group, in the order they are stored.
+fn group_ids(group: &PrimitiveGroup) -> Vec<(OsmEntityType, i64)> {
+    let mut ids = Vec::new();
+    let mut dense_id = 0;
+    for delta in group.get_dense().get_id() {
+        dense_id += delta;
+        ids.push((OsmEntityType::Node, dense_id));
+    }
+    ids.extend(group.get_nodes().iter().map(|node| (OsmEntityType::Node, node.get_id())));
+    ids.extend(group.get_ways().iter().map(|way| (OsmEntityType::Way, way.get_id())));
+    ids.extend(group.get_relations().iter().map(|relation| (OsmEntityType::Relation, relation.get_id())));
+    ids
+}
+
+impl BlobVisitor for InfoVisitor {
+    fn visit_blob(&mut self, blob: &Blob) -> VisitResult {
+        let layout = blob.layout.expect("blobs read from a file have a layout");
+        let (index, offset) = (self.index, self.offset);
+        self.index += 1;
+        self.offset += layout.stored_size();
+
+        let block = match blob.data_type {
+            BlobType::HEADER => {
+                let header: HeaderBlock = ::read_message_bytes(&blob.data)?;
+                if self.info.header.is_none() {
+                    self.info.header = Some(header);
+                }
+                None
+            }
+            BlobType::DATA => {
+                let block: PrimitiveBlock = ::read_message_bytes(&blob.data)?;
+                let summary = self.summarize_block(&block);
+                for (entity_type, range) in &summary.entities {
+                    let total = self.info.entities.entry(*entity_type).or_insert(IdRange { count: 0, min: range.min, max: range.max });
+                    total.merge(range);
+                }
+                Some(summary)
+            }
+        };
+
+        self.info.blobs.push(BlobSummary { index, offset, data_type: blob.data_type, layout, raw_size: blob.data.len(), block });
+        Ok(Flow::Continue)
+    }
+
+    fn end(&mut self) -> Result<(), PbfParseError> {
+        Ok(())
+    }
+}
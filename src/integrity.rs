@@ -0,0 +1,237 @@
+use ::PbfParseError;
+use idset::IdSet;
+use location::{Location, NodeLocationStore};
+use osm::{EntityInfo, MemberReference, NodeReference, OsmEntityType, OsmReader};
+use reader::ErrorSummary;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Read, Seek};
+use visitor::{Flow, OsmVisitor, VisitResult};
+
+/// A problem found by an `IntegrityCheck`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IntegrityProblem {
+    /// An entity is stored more than once
+    DuplicateId { entity_type: OsmEntityType, id: i64 },
+    /// A node's latitude is outside -90 to 90 degrees or its longitude outside -180 to 180 degrees
+    InvalidLocation { node_id: i64, latitude: f64, longitude: f64 },
+    /// A way has fewer than two nodes, so it has no segments
+    TooFewNodes { way_id: i64, count: usize },
+    /// A way references the same node twice in a row
+    RepeatedNode { way_id: i64, node_id: i64 },
+    /// Consecutive nodes of a way are different nodes at the same location
+    ZeroLengthSegment { way_id: i64, from_node: i64, to_node: i64 },
+    /// A way references a node not present in the file
+    MissingNode { way_id: i64, node_id: i64 },
+    /// A relation references a member not present in the file
+    MissingMember { relation_id: i64, member_type: OsmEntityType, member_id: i64 },
+}
+
+impl IntegrityProblem {
+    /// A short name for the kind of problem, for counting problems by kind.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            IntegrityProblem::DuplicateId { .. } => "duplicate id",
+            IntegrityProblem::InvalidLocation { .. } => "invalid location",
+            IntegrityProblem::TooFewNodes { .. } => "too few nodes",
+            IntegrityProblem::RepeatedNode { .. } => "repeated node",
+            IntegrityProblem::ZeroLengthSegment { .. } => "zero-length segment",
+            IntegrityProblem::MissingNode { .. } => "missing node",
+            IntegrityProblem::MissingMember { .. } => "missing member",
+        }
+    }
+}
+
+impl fmt::Display for IntegrityProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IntegrityProblem::DuplicateId { entity_type, id } => write!(f, "{} {} is stored more than once", entity_type, id),
+            IntegrityProblem::InvalidLocation { node_id, latitude, longitude } => {
+                write!(f, "node {} has invalid location {},{}", node_id, latitude, longitude)
+            }
+            IntegrityProblem::TooFewNodes { way_id, count } => write!(f, "way {} has only {} nodes", way_id, count),
+            IntegrityProblem::RepeatedNode { way_id, node_id } => write!(f, "way {} references node {} twice in a row", way_id, node_id),
+            IntegrityProblem::ZeroLengthSegment { way_id, from_node, to_node } => {
+                write!(f, "way {} has a zero-length segment from node {} to node {}", way_id, from_node, to_node)
+            }
+            IntegrityProblem::MissingNode { way_id, node_id } => write!(f, "way {} references missing node {}", way_id, node_id),
+            IntegrityProblem::MissingMember { relation_id, member_type, member_id } => {
+                write!(f, "relation {} references missing {} {}", relation_id, member_type, member_id)
+            }
+        }
+    }
+}
+
+/// The problems found by an `IntegrityCheck`.
+#[derive(Debug, Clone, Default)]
+pub struct IntegrityReport {
+    /// The problems found, up to the check's limit
+    pub problems: Vec<IntegrityProblem>,
+    /// The number of problems of each kind, including those beyond the limit
+    pub counts: BTreeMap<&'static str, usize>,
+}
+
+impl IntegrityReport {
+    pub fn is_clean(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// The number of problems found, including those beyond the limit.
+    pub fn total(&self) -> usize {
+        self.counts.values().sum()
+    }
+
+    fn add(&mut self, problem: IntegrityProblem, max_problems: usize) {
+        *self.counts.entry(problem.kind()).or_insert(0) += 1;
+        if self.problems.len() < max_problems {
+            self.problems.push(problem);
+        }
+    }
+}
+
+/// Checks the referential integrity of a file, such as before publishing an extract.
+///
+/// The first pass collects the IDs of all entities and stores node locations, finding duplicate IDs, invalid
+/// locations and ways with too few or repeated nodes. The second finds references to entities missing from the
+/// file and way segments of zero length, so neither depends on the order of the file.
+pub struct IntegrityCheck<'a> {
+    store: &'a mut NodeLocationStore,
+    max_problems: usize,
+}
+
+impl<'a> IntegrityCheck<'a> {
+    pub fn new(store: &'a mut NodeLocationStore) -> IntegrityCheck<'a> {
+        IntegrityCheck { store, max_problems: usize::max_value() }
+    }
+
+    /// Sets how many problems are listed in the report, beyond which they are only counted.
+    pub fn set_max_problems(&mut self, max_problems: usize) {
+        self.max_problems = max_problems;
+    }
+
+    pub fn run<T: Read + Seek>(&mut self, reader: &mut OsmReader<T>) -> Result<(IntegrityReport, ErrorSummary), PbfParseError> {
+        let mut collector = IdCollector {
+            store: &mut *self.store,
+            max_problems: self.max_problems,
+            nodes: IdSet::new(),
+            ways: IdSet::new(),
+            relations: IdSet::new(),
+            report: IntegrityReport::default(),
+        };
+        let mut summary = reader.accept(&mut collector)?;
+
+        let IdCollector { store, nodes, ways, relations, report, .. } = collector;
+        let mut checker = ReferenceChecker {
+            store: &*store,
+            max_problems: self.max_problems,
+            nodes: &nodes,
+            ways: &ways,
+            relations: &relations,
+            report,
+        };
+        summary.merge(reader.accept(&mut checker)?);
+        Ok((checker.report, summary))
+    }
+}
+
+fn is_valid_location(latitude: f64, longitude: f64) -> bool {
+    latitude >= -90.0 && latitude <= 90.0 && longitude >= -180.0 && longitude <= 180.0
+}
+
+struct IdCollector<'a> {
+    store: &'a mut NodeLocationStore,
+    max_problems: usize,
+    nodes: IdSet,
+    ways: IdSet,
+    relations: IdSet,
+    report: IntegrityReport,
+}
+
+impl<'a> IdCollector<'a> {
+    fn add_id(&mut self, entity_type: OsmEntityType, id: i64) {
+        let inserted = match entity_type {
+            OsmEntityType::Node => self.nodes.insert(id),
+            OsmEntityType::Way => self.ways.insert(id),
+            OsmEntityType::Relation => self.relations.insert(id),
+        };
+        if !inserted {
+            self.report.add(IntegrityProblem::DuplicateId { entity_type, id }, self.max_problems);
+        }
+    }
+}
+
+impl<'a> OsmVisitor for IdCollector<'a> {
+    fn visit_node(&mut self, id: i64, latitude: f64, longitude: f64, _tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        self.add_id(OsmEntityType::Node, id);
+        if is_valid_location(latitude, longitude) {
+            self.store.set(id, Location::new(latitude, longitude))?;
+        } else {
+            self.report.add(IntegrityProblem::InvalidLocation { node_id: id, latitude, longitude }, self.max_problems);
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn visit_way(&mut self, id: i64, nodes: Vec<NodeReference>, _tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        self.add_id(OsmEntityType::Way, id);
+        if nodes.len() < 2 {
+            self.report.add(IntegrityProblem::TooFewNodes { way_id: id, count: nodes.len() }, self.max_problems);
+        }
+        for pair in nodes.windows(2) {
+            if pair[0].id == pair[1].id {
+                self.report.add(IntegrityProblem::RepeatedNode { way_id: id, node_id: pair[0].id }, self.max_problems);
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn visit_relation(&mut self, id: i64, _members: Vec<MemberReference>, _tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        self.add_id(OsmEntityType::Relation, id);
+        Ok(Flow::Continue)
+    }
+}
+
+struct ReferenceChecker<'a> {
+    store: &'a NodeLocationStore,
+    max_problems: usize,
+    nodes: &'a IdSet,
+    ways: &'a IdSet,
+    relations: &'a IdSet,
+    report: IntegrityReport,
+}
+
+impl<'a> OsmVisitor for ReferenceChecker<'a> {
+    fn visit_way(&mut self, id: i64, nodes: Vec<NodeReference>, _tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        for node in &nodes {
+            if !self.nodes.contains(node.id) {
+                self.report.add(IntegrityProblem::MissingNode { way_id: id, node_id: node.id }, self.max_problems);
+            }
+        }
+        for pair in nodes.windows(2) {
+            if pair[0].id == pair[1].id {
+                continue;
+            }
+            // Locations are compared at the precision they are stored with, which is that of the file format
+            let from = self.store.get(pair[0].id).map(|location| location.to_fixed());
+            let to = self.store.get(pair[1].id).map(|location| location.to_fixed());
+            if from.is_some() && from == to {
+                self.report.add(IntegrityProblem::ZeroLengthSegment { way_id: id, from_node: pair[0].id, to_node: pair[1].id }, self.max_problems);
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn visit_relation(&mut self, id: i64, members: Vec<MemberReference>, _tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        for member in &members {
+            let present = match member.entity_type {
+                OsmEntityType::Node => self.nodes.contains(member.id),
+                OsmEntityType::Way => self.ways.contains(member.id),
+                OsmEntityType::Relation => self.relations.contains(member.id),
+            };
+            if !present {
+                let problem = IntegrityProblem::MissingMember { relation_id: id, member_type: member.entity_type, member_id: member.id };
+                self.report.add(problem, self.max_problems);
+            }
+        }
+        Ok(Flow::Continue)
+    }
+}
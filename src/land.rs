@@ -0,0 +1,222 @@
+use ::PbfParseError;
+use coastline::Coastline;
+use geojson::GeoJsonWriter;
+use geometry::{BoundingBox, Chain, clip_ring, Polygon, ring_contains, signed_area};
+use location::Location;
+use std::cmp::Ordering;
+use std::io::Write;
+
+/// How far from the edge of the map, in degrees, a coastline may end and still be treated as touching it
+const FRAME_TOLERANCE: f64 = 1e-6;
+/// The length of the map frame, measured counter-clockwise from its bottom left corner as 360 degrees along the
+/// bottom edge, 180 up the right edge, 360 along the top and 180 down the left edge
+const FRAME_PERIMETER: f64 = 1080.0;
+const FRAME_CORNERS: [(f64, f64, f64); 4] = [
+    (0.0, -90.0, -180.0),
+    (360.0, -90.0, 180.0),
+    (540.0, 90.0, 180.0),
+    (900.0, 90.0, -180.0),
+];
+
+/// Land polygons built from a coastline.
+#[derive(Debug, Clone, Default)]
+pub struct LandPolygons {
+    pub polygons: Vec<Polygon>,
+    /// The ways of each open chain that doesn't reach the edge of the map at both ends, and so was left out
+    pub unclosed: Vec<Vec<i64>>,
+}
+
+impl LandPolygons {
+    pub fn write_geojson(&self, output: &mut Write) -> Result<(), PbfParseError> {
+        let mut writer = GeoJsonWriter::new(output)?;
+        for polygon in &self.polygons {
+            writer.write_polygon(polygon, &[])?;
+        }
+        writer.finish()
+    }
+}
+
+/// Builds land polygons from coastline rings, with land to the left of the coastline.
+///
+/// Closed counter-clockwise rings become land, and clockwise rings become holes in the land ring around them.
+/// Coastlines that cross the antimeridian are split there in OSM data, leaving open chains that start and end on
+/// the edge of the map. These are closed by following the edge of the map counter-clockwise from the end of each
+/// chain to the start of the next one, which for Antarctica runs along the bottom edge so that the land includes
+/// the south pole.
+#[derive(Debug, Clone, Default)]
+pub struct LandGenerator {
+    grid_size: Option<f64>,
+}
+
+impl LandGenerator {
+    pub fn new() -> LandGenerator {
+        LandGenerator { grid_size: None }
+    }
+
+    /// Splits the land into cells of the given size in degrees, aligned to the map's bottom left corner. Smaller
+    /// polygons are much faster to render than whole continents.
+    pub fn set_grid_size(&mut self, grid_size: f64) {
+        self.grid_size = Some(grid_size);
+    }
+
+    pub fn generate(&self, coastline: &Coastline) -> LandPolygons {
+        let mut outers = Vec::new();
+        let mut holes = Vec::new();
+        for ring in &coastline.rings {
+            let locations = ring.locations();
+            if signed_area(&locations) > 0.0 {
+                outers.push(locations);
+            } else {
+                holes.push(locations);
+            }
+        }
+
+        let (framed, unclosed) = close_along_frame(&coastline.open);
+        outers.extend(framed);
+
+        let polygons = nest_holes(outers, holes);
+        let polygons = match self.grid_size {
+            Some(grid_size) => polygons.iter().flat_map(|polygon| split_into_grid(polygon, grid_size)).collect(),
+            None => polygons,
+        };
+        LandPolygons { polygons, unclosed }
+    }
+}
+
+/// The position along the map frame of a location on its edge, or `None` if it is not on the edge.
+fn frame_position(location: Location) -> Option<f64> {
+    if location.latitude <= -90.0 + FRAME_TOLERANCE {
+        Some(location.longitude + 180.0)
+    } else if location.longitude >= 180.0 - FRAME_TOLERANCE {
+        Some(360.0 + location.latitude + 90.0)
+    } else if location.latitude >= 90.0 - FRAME_TOLERANCE {
+        Some(540.0 + 180.0 - location.longitude)
+    } else if location.longitude <= -180.0 + FRAME_TOLERANCE {
+        Some(900.0 + 90.0 - location.latitude)
+    } else {
+        None
+    }
+}
+
+fn frame_distance(from: f64, to: f64) -> f64 {
+    ((to - from) % FRAME_PERIMETER + FRAME_PERIMETER) % FRAME_PERIMETER
+}
+
+/// Closes open chains that start and end on the edge of the map into rings, returning the rings along with the
+/// ways of the chains that don't reach the edge.
+fn close_along_frame(open: &[Chain]) -> (Vec<Vec<Location>>, Vec<Vec<i64>>) {
+    let mut unclosed = Vec::new();
+    let mut framed: Vec<(&Chain, f64, f64)> = Vec::new();
+    for chain in open {
+        let start = frame_position(chain.nodes[0].1);
+        let end = frame_position(chain.nodes[chain.nodes.len() - 1].1);
+        match (start, end) {
+            (Some(start), Some(end)) => framed.push((chain, start, end)),
+            _ => unclosed.push(chain.way_ids()),
+        }
+    }
+
+    let mut rings = Vec::new();
+    let mut used = vec![false; framed.len()];
+    for first in 0..framed.len() {
+        if used[first] {
+            continue;
+        }
+        used[first] = true;
+
+        let mut ring: Vec<Location> = framed[first].0.locations();
+        let mut current = first;
+        loop {
+            let end = framed[current].2;
+            // The next chain is the one starting nearest counter-clockwise along the frame
+            let next = (0..framed.len())
+                .filter(|index| !used[*index] || *index == first)
+                .min_by(|a, b| {
+                    let a = frame_distance(end, framed[*a].1);
+                    let b = frame_distance(end, framed[*b].1);
+                    a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+                })
+                .expect("the first chain is always a candidate");
+
+            let distance = frame_distance(end, framed[next].1);
+            let mut corners: Vec<(f64, Location)> = FRAME_CORNERS.iter()
+                .map(|&(position, latitude, longitude)| (frame_distance(end, position), Location::new(latitude, longitude)))
+                .filter(|&(corner_distance, _)| corner_distance > 0.0 && corner_distance < distance)
+                .collect();
+            corners.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+            ring.extend(corners.into_iter().map(|corner| corner.1));
+
+            if next == first {
+                break;
+            }
+            used[next] = true;
+            ring.extend(framed[next].0.locations());
+            current = next;
+        }
+
+        let start = ring[0];
+        ring.push(start);
+        rings.push(ring);
+    }
+
+    (rings, unclosed)
+}
+
+/// Places each hole inside the smallest outer ring containing it. Holes outside of any outer ring are dropped.
+fn nest_holes(outers: Vec<Vec<Location>>, holes: Vec<Vec<Location>>) -> Vec<Polygon> {
+    let mut outers: Vec<(f64, Vec<Location>, Vec<Vec<Location>>)> = outers.into_iter()
+        .map(|ring| (signed_area(&ring).abs(), ring, Vec::new()))
+        .collect();
+    outers.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+
+    for hole in holes {
+        // Holes often share nodes with the land around them, so probe with the middle of a segment
+        let (a, b) = (hole[0], hole[1]);
+        let probe = Location::new((a.latitude + b.latitude) / 2.0, (a.longitude + b.longitude) / 2.0);
+        let area = signed_area(&hole).abs();
+        if let Some(outer) = outers.iter_mut().find(|outer| outer.0 > area && ring_contains(&outer.1, probe)) {
+            outer.2.push(hole);
+        }
+    }
+
+    outers.into_iter().map(|(_, exterior, interiors)| Polygon::new(exterior, interiors)).collect()
+}
+
+/// Splits a polygon into the cells of a grid, clipping first into columns and then each column into cells so that
+/// large polygons are not clipped against every cell.
+fn split_into_grid(polygon: &Polygon, grid_size: f64) -> Vec<Polygon> {
+    let bounds = match BoundingBox::from_locations(&polygon.exterior) {
+        Some(bounds) => bounds,
+        None => return Vec::new(),
+    };
+    let cell_index = |value: f64, origin: f64| ((value - origin) / grid_size).floor() as i64;
+
+    let mut cells = Vec::new();
+    for column in cell_index(bounds.left, -180.0)..cell_index(bounds.right, -180.0) + 1 {
+        let left = -180.0 + column as f64 * grid_size;
+        let strip = BoundingBox::new(left, bounds.bottom, left + grid_size, bounds.top);
+        let exterior = clip_ring(&polygon.exterior, &strip);
+        if exterior.is_empty() {
+            continue;
+        }
+        let interiors: Vec<Vec<Location>> = polygon.interiors.iter()
+            .map(|ring| clip_ring(ring, &strip))
+            .filter(|ring| !ring.is_empty())
+            .collect();
+
+        for row in cell_index(bounds.bottom, -90.0)..cell_index(bounds.top, -90.0) + 1 {
+            let bottom = -90.0 + row as f64 * grid_size;
+            let cell = BoundingBox::new(left, bottom, left + grid_size, bottom + grid_size);
+            let cell_exterior = clip_ring(&exterior, &cell);
+            if cell_exterior.is_empty() || signed_area(&cell_exterior) == 0.0 {
+                continue;
+            }
+            let cell_interiors = interiors.iter()
+                .map(|ring| clip_ring(ring, &cell))
+                .filter(|ring| !ring.is_empty() && signed_area(ring) != 0.0)
+                .collect();
+            cells.push(Polygon::new(cell_exterior, cell_interiors));
+        }
+    }
+    cells
+}
@@ -0,0 +1,152 @@
+#![feature(try_from)]
+
+extern crate byteorder;
+extern crate flate2;
+extern crate memmap;
+extern crate protobuf;
+extern crate serde_json;
+extern crate xml;
+
+use osm::OsmEntityType;
+use std::convert::From;
+use std::fmt;
+use std::io::Read;
+
+pub mod protos;
+pub mod blob;
+pub mod visitor;
+pub mod reader;
+pub mod writer;
+pub mod osm;
+pub mod combinator;
+pub mod pipeline;
+pub mod idset;
+pub mod location;
+pub mod geometry;
+pub mod multipolygon;
+pub mod geojson;
+pub mod coastline;
+pub mod land;
+pub mod extract;
+pub mod boundary;
+pub mod filter;
+pub mod inspect;
+pub mod tagstats;
+pub mod merge;
+pub mod sort;
+pub mod renumber;
+pub mod integrity;
+pub mod change;
+
+pub fn read_message<M: protobuf::Message>(reader: &mut Read, length: usize) -> Result<M, PbfParseError> {
+    let mut buffer = vec!(0u8; length as usize);
+    reader.read_exact(&mut buffer)?;
+    Ok(protobuf::parse_from_bytes(&buffer)?)
+}
+
+pub fn read_message_bytes<M: protobuf::Message>(buffer: &[u8]) -> Result<M, PbfParseError> {
+    Ok(protobuf::parse_from_bytes(buffer)?)
+}
+
+#[derive(Debug)]
+pub enum PbfParseError {
+    Io(std::io::Error),
+    Eof,
+    Decompression(std::io::Error),
+    InvalidHeaderLength(u32),
+    InvalidBodyLength(u32),
+    InvalidMessage(protobuf::ProtobufError),
+    InvalidBlobFormat,
+    InvalidBlobType(String),
+    MalformedData(String),
+    /// Wraps an error that occurred while reading the blob at the given index and byte offset
+    InBlob { index: usize, offset: u64, source: Box<PbfParseError> },
+    /// Wraps an error that occurred while reading or visiting a single element
+    InElement { entity_type: OsmEntityType, id: i64, source: Box<PbfParseError> },
+}
+
+/// The broad category of a `PbfParseError`, looking through any location context.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum PbfErrorKind {
+    Io,
+    Decompression,
+    Protobuf,
+    Semantic,
+}
+
+impl PbfParseError {
+    pub fn in_blob(self, index: usize, offset: u64) -> PbfParseError {
+        PbfParseError::InBlob { index, offset, source: Box::new(self) }
+    }
+
+    pub fn in_element(self, entity_type: OsmEntityType, id: i64) -> PbfParseError {
+        PbfParseError::InElement { entity_type, id, source: Box::new(self) }
+    }
+
+    pub fn kind(&self) -> PbfErrorKind {
+        match *self {
+            PbfParseError::Io(_) | PbfParseError::Eof => PbfErrorKind::Io,
+            PbfParseError::Decompression(_) => PbfErrorKind::Decompression,
+            PbfParseError::InvalidMessage(_) => PbfErrorKind::Protobuf,
+            PbfParseError::InBlob { ref source, .. } | PbfParseError::InElement { ref source, .. } => source.kind(),
+            _ => PbfErrorKind::Semantic,
+        }
+    }
+
+    /// Returns the innermost error, without any location context.
+    pub fn root(&self) -> &PbfParseError {
+        match *self {
+            PbfParseError::InBlob { ref source, .. } | PbfParseError::InElement { ref source, .. } => source.root(),
+            _ => self,
+        }
+    }
+}
+
+impl fmt::Display for PbfParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PbfParseError::Io(ref e) => write!(f, "i/o error: {}", e),
+            PbfParseError::Eof => write!(f, "unexpected end of file"),
+            PbfParseError::Decompression(ref e) => write!(f, "failed to decompress blob: {}", e),
+            PbfParseError::InvalidHeaderLength(len) => write!(f, "invalid blob header length {}", len),
+            PbfParseError::InvalidBodyLength(len) => write!(f, "invalid blob body length {}", len),
+            PbfParseError::InvalidMessage(ref e) => write!(f, "invalid protobuf message: {}", e),
+            PbfParseError::InvalidBlobFormat => write!(f, "unsupported blob compression format"),
+            PbfParseError::InvalidBlobType(ref ty) => write!(f, "unknown blob type {:?}", ty),
+            PbfParseError::MalformedData(ref msg) => write!(f, "malformed data: {}", msg),
+            PbfParseError::InBlob { index, offset, ref source } => {
+                write!(f, "in blob {} at byte offset {}: {}", index, offset, source)
+            }
+            PbfParseError::InElement { entity_type, id, ref source } => {
+                write!(f, "in {} {}: {}", entity_type, id, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PbfParseError {
+    fn source(&self) -> Option<&(std::error::Error + 'static)> {
+        match *self {
+            PbfParseError::Io(ref e) | PbfParseError::Decompression(ref e) => Some(e),
+            PbfParseError::InvalidMessage(ref e) => Some(e),
+            PbfParseError::InBlob { ref source, .. } | PbfParseError::InElement { ref source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for PbfParseError {
+    fn from(err: std::io::Error) -> Self {
+        if err.kind() == std::io::ErrorKind::UnexpectedEof {
+            PbfParseError::Eof
+        } else {
+            PbfParseError::Io(err)
+        }
+    }
+}
+
+impl From<protobuf::ProtobufError> for PbfParseError {
+    fn from(err: protobuf::ProtobufError) -> Self {
+        PbfParseError::InvalidMessage(err)
+    }
+}
@@ -0,0 +1,347 @@
+use ::PbfParseError;
+use byteorder::{ByteOrder, LittleEndian};
+use memmap::{MmapMut, MmapOptions};
+use osm::{EntityInfo, NodeReference};
+use std::fs::{File, OpenOptions};
+use std::i32;
+use std::path::Path;
+use visitor::{ErrorPolicy, Flow, OsmVisitor, VisitResult};
+
+/// Locations are stored as fixed point values in units of 1e-7 degrees, the default precision of OSM data.
+const LOCATION_UNIT: f64 = 1e-7;
+const UNSET: i32 = i32::MIN;
+
+const STORE_FILE_MAGIC: &[u8; 8] = b"OSMLOC01";
+const STORE_FILE_HEADER_LENGTH: u64 = 16;
+const STORE_FILE_INITIAL_LENGTH: u64 = 64 * 1024 * 1024;
+/// Latitudes are stored with this bias in location files, so that the zeroed holes of a sparse file read as unset
+const STORE_FILE_LATITUDE_BIAS: i32 = 1_000_000_000;
+/// The highest node ID stored in the file, well beyond the IDs in use today, so that the file stays within 512 GiB
+const STORE_FILE_MAX_ID: i64 = 1 << 36;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Location {
+    pub fn new(latitude: f64, longitude: f64) -> Location {
+        Location { latitude, longitude }
+    }
+
+    pub fn to_fixed(&self) -> (i32, i32) {
+        ((self.latitude / LOCATION_UNIT).round() as i32, (self.longitude / LOCATION_UNIT).round() as i32)
+    }
+
+    pub fn from_fixed(latitude: i32, longitude: i32) -> Location {
+        Location { latitude: latitude as f64 * LOCATION_UNIT, longitude: longitude as f64 * LOCATION_UNIT }
+    }
+}
+
+/// Maps node IDs to their locations, so that way geometries can be built after the node section has been read.
+pub trait NodeLocationStore {
+    fn set(&mut self, id: i64, location: Location) -> Result<(), PbfParseError>;
+
+    fn get(&self, id: i64) -> Option<Location>;
+}
+
+/// Stores locations in a vector sorted by node ID. Best suited to extracts, where the node IDs are sparse.
+#[derive(Debug, Default)]
+pub struct SparseLocationStore {
+    entries: Vec<(i64, i32, i32)>,
+}
+
+impl SparseLocationStore {
+    pub fn new() -> SparseLocationStore {
+        SparseLocationStore { entries: Vec::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl NodeLocationStore for SparseLocationStore {
+    fn set(&mut self, id: i64, location: Location) -> Result<(), PbfParseError> {
+        let (latitude, longitude) = location.to_fixed();
+        // Nodes usually arrive in ascending order, so appending is the common case
+        if self.entries.last().map_or(true, |last| last.0 < id) {
+            self.entries.push((id, latitude, longitude));
+        } else {
+            match self.entries.binary_search_by_key(&id, |entry| entry.0) {
+                Ok(index) => self.entries[index] = (id, latitude, longitude),
+                Err(index) => self.entries.insert(index, (id, latitude, longitude)),
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&self, id: i64) -> Option<Location> {
+        self.entries.binary_search_by_key(&id, |entry| entry.0).ok()
+            .map(|index| Location::from_fixed(self.entries[index].1, self.entries[index].2))
+    }
+}
+
+/// Stores locations in an array indexed directly by node ID, using 8 bytes for every ID up to the highest one
+/// stored. Best suited to large inputs where most IDs are present. Negative IDs are kept separately.
+#[derive(Debug, Default)]
+pub struct DenseLocationStore {
+    locations: Vec<(i32, i32)>,
+    negative: SparseLocationStore,
+}
+
+impl DenseLocationStore {
+    pub fn new() -> DenseLocationStore {
+        DenseLocationStore { locations: Vec::new(), negative: SparseLocationStore::new() }
+    }
+
+    pub fn with_capacity(max_id: usize) -> DenseLocationStore {
+        DenseLocationStore { locations: Vec::with_capacity(max_id + 1), negative: SparseLocationStore::new() }
+    }
+}
+
+impl NodeLocationStore for DenseLocationStore {
+    fn set(&mut self, id: i64, location: Location) -> Result<(), PbfParseError> {
+        if id < 0 {
+            return self.negative.set(id, location);
+        }
+        let index = id as usize;
+        if index >= self.locations.len() {
+            self.locations.resize(index + 1, (UNSET, UNSET));
+        }
+        self.locations[index] = location.to_fixed();
+        Ok(())
+    }
+
+    fn get(&self, id: i64) -> Option<Location> {
+        if id < 0 {
+            return self.negative.get(id);
+        }
+        match self.locations.get(id as usize) {
+            Some(&(latitude, longitude)) if latitude != UNSET => Some(Location::from_fixed(latitude, longitude)),
+            _ => None,
+        }
+    }
+}
+
+/// Stores locations in a flat file indexed by node ID, accessed through a memory map so that planet-sized stores
+/// don't need to fit in memory. The file is sparse where IDs are unused and can be reopened to reuse the locations
+/// in later runs. Negative IDs are kept in memory and are not persisted. Latitudes beyond the poles and IDs above
+/// 2^36 are rejected with `PbfParseError::MalformedData`.
+pub struct MmapLocationStore {
+    file: File,
+    map: MmapMut,
+    negative: SparseLocationStore,
+}
+
+impl MmapLocationStore {
+    /// Creates a new, empty store at the given path, replacing any existing file.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<MmapLocationStore, PbfParseError> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        file.set_len(STORE_FILE_HEADER_LENGTH + STORE_FILE_INITIAL_LENGTH)?;
+        let mut map = unsafe { MmapOptions::new().map_mut(&file)? };
+        map[..STORE_FILE_MAGIC.len()].copy_from_slice(STORE_FILE_MAGIC);
+        Ok(MmapLocationStore { file, map, negative: SparseLocationStore::new() })
+    }
+
+    /// Opens a store previously written with `create`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MmapLocationStore, PbfParseError> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        if file.metadata()?.len() < STORE_FILE_HEADER_LENGTH {
+            return Err(PbfParseError::MalformedData("location store file is too short".to_string()));
+        }
+        let map = unsafe { MmapOptions::new().map_mut(&file)? };
+        if &map[..STORE_FILE_MAGIC.len()] != STORE_FILE_MAGIC {
+            return Err(PbfParseError::MalformedData("not a location store file".to_string()));
+        }
+        Ok(MmapLocationStore { file, map, negative: SparseLocationStore::new() })
+    }
+
+    /// Writes all changes through to the file.
+    pub fn flush(&self) -> Result<(), PbfParseError> {
+        self.map.flush()?;
+        Ok(())
+    }
+
+    fn grow(&mut self, min_length: u64) -> Result<(), PbfParseError> {
+        let mut length = self.map.len() as u64;
+        while length < min_length {
+            length = length.checked_mul(2)
+                .ok_or_else(|| PbfParseError::MalformedData(format!("location store can't grow to {} bytes", min_length)))?;
+        }
+        self.map.flush()?;
+        self.file.set_len(length)?;
+        self.map = unsafe { MmapOptions::new().map_mut(&self.file)? };
+        Ok(())
+    }
+}
+
+impl NodeLocationStore for MmapLocationStore {
+    fn set(&mut self, id: i64, location: Location) -> Result<(), PbfParseError> {
+        if id < 0 {
+            return self.negative.set(id, location);
+        }
+        // Latitudes beyond the poles would overflow the bias, or be biased to zero and read back as unset
+        if !(location.latitude >= -90.0 && location.latitude <= 90.0) {
+            return Err(PbfParseError::MalformedData(format!("node {} has invalid latitude {}", id, location.latitude)));
+        }
+        let offset = entry_offset(id)
+            .ok_or_else(|| PbfParseError::MalformedData(format!("node ID {} is too large for a location store", id)))?;
+        if offset + 8 > self.map.len() as u64 {
+            self.grow(offset + 8)?;
+        }
+        let (latitude, longitude) = location.to_fixed();
+        let entry = &mut self.map[offset as usize..offset as usize + 8];
+        LittleEndian::write_i32(&mut entry[..4], latitude + STORE_FILE_LATITUDE_BIAS);
+        LittleEndian::write_i32(&mut entry[4..], longitude);
+        Ok(())
+    }
+
+    fn get(&self, id: i64) -> Option<Location> {
+        if id < 0 {
+            return self.negative.get(id);
+        }
+        let offset = match entry_offset(id) {
+            Some(offset) if offset + 8 <= self.map.len() as u64 => offset,
+            _ => return None,
+        };
+        let entry = &self.map[offset as usize..offset as usize + 8];
+        match LittleEndian::read_i32(&entry[..4]) {
+            0 => None,
+            latitude => Some(Location::from_fixed(latitude - STORE_FILE_LATITUDE_BIAS, LittleEndian::read_i32(&entry[4..]))),
+        }
+    }
+}
+
+/// The offset of a node's entry in a location store file, or `None` if the ID is beyond the highest stored.
+fn entry_offset(id: i64) -> Option<u64> {
+    if id > STORE_FILE_MAX_ID {
+        return None;
+    }
+    (id as u64).checked_mul(8).and_then(|offset| offset.checked_add(STORE_FILE_HEADER_LENGTH))
+}
+
+/// A way whose node references have been resolved to locations.
+#[derive(Debug, Clone)]
+pub struct WayGeometry {
+    pub id: i64,
+    /// The way's nodes in order, omitting any whose location is unknown
+    pub nodes: Vec<(i64, Location)>,
+    /// The IDs of referenced nodes whose location is not in the store
+    pub missing: Vec<i64>,
+    pub tags: Vec<(String, String)>,
+    pub info: EntityInfo,
+}
+
+impl WayGeometry {
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    pub fn locations(&self) -> Vec<Location> {
+        self.nodes.iter().map(|node| node.1).collect()
+    }
+}
+
+pub trait WayGeometryVisitor {
+    fn visit_way_geometry(&mut self, way: WayGeometry) -> VisitResult;
+
+    fn end(&mut self) -> Result<(), PbfParseError> {
+        Ok(())
+    }
+
+    fn handle_error(&mut self, _error: &PbfParseError) -> ErrorPolicy {
+        ErrorPolicy::SkipBlob
+    }
+}
+
+/// Stores the location of every node it visits, and hands every way to the delegate with its node locations
+/// resolved from the store. In a file sorted by type, a single pass is enough since nodes come before ways.
+pub struct LocationResolver<'a> {
+    store: &'a mut NodeLocationStore,
+    delegate: &'a mut WayGeometryVisitor,
+    store_nodes: bool,
+    missing_count: usize,
+}
+
+impl<'a> LocationResolver<'a> {
+    pub fn new(store: &'a mut NodeLocationStore, delegate: &'a mut WayGeometryVisitor) -> LocationResolver<'a> {
+        LocationResolver { store, delegate, store_nodes: true, missing_count: 0 }
+    }
+
+    /// Resolves ways against a store that has already been populated, without adding to it.
+    pub fn from_populated(store: &'a mut NodeLocationStore, delegate: &'a mut WayGeometryVisitor) -> LocationResolver<'a> {
+        LocationResolver { store, delegate, store_nodes: false, missing_count: 0 }
+    }
+
+    /// The total number of way node references that could not be resolved.
+    pub fn missing_count(&self) -> usize {
+        self.missing_count
+    }
+}
+
+impl<'a> OsmVisitor for LocationResolver<'a> {
+    fn visit_node(&mut self, id: i64, latitude: f64, longitude: f64, _tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        if self.store_nodes {
+            self.store.set(id, Location::new(latitude, longitude))?;
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn visit_way(&mut self, id: i64, nodes: Vec<NodeReference>, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+        let mut resolved = Vec::with_capacity(nodes.len());
+        let mut missing = Vec::new();
+        for node in nodes {
+            match self.store.get(node.id) {
+                Some(location) => resolved.push((node.id, location)),
+                None => missing.push(node.id),
+            }
+        }
+        self.missing_count += missing.len();
+        self.delegate.visit_way_geometry(WayGeometry { id, nodes: resolved, missing, tags, info })
+    }
+
+    fn end(&mut self) -> Result<(), PbfParseError> {
+        self.delegate.end()
+    }
+
+    fn handle_error(&mut self, error: &PbfParseError) -> ErrorPolicy {
+        self.delegate.handle_error(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn rejects_invalid_entries_without_growing_the_store_file() {
+        let path = env::temp_dir().join(format!("osm-location-store-test-{}", process::id()));
+        {
+            let mut store = MmapLocationStore::create(&path).unwrap();
+            let length = store.map.len();
+            for &latitude in &[90.5, -100.0, 200.0] {
+                assert!(store.set(STORE_FILE_MAX_ID, Location::new(latitude, 0.0)).is_err());
+            }
+            assert!(store.set(STORE_FILE_MAX_ID + 1, Location::new(0.0, 0.0)).is_err());
+            assert!(store.set(i64::max_value(), Location::new(0.0, 0.0)).is_err());
+            assert_eq!(store.map.len(), length);
+            assert_eq!(store.get(i64::max_value()), None);
+
+            store.set(42, Location::new(-90.0, 180.0)).unwrap();
+            store.set(-7, Location::new(1.5, -2.5)).unwrap();
+            assert_eq!(store.get(42), Some(Location::new(-90.0, 180.0)));
+            assert_eq!(store.get(-7), Some(Location::new(1.5, -2.5)));
+            assert_eq!(store.get(43), None);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,162 +1,751 @@
-#![feature(try_from)]
-
-extern crate byteorder;
+#[macro_use]
+extern crate clap;
 extern crate flate2;
-extern crate protobuf;
-
-use osm::{MemberReference, NodeReference, OsmReader, EntityInfo};
-use protos::osm::HeaderBlock;
-use reader::BlobReader;
-use std::collections::HashSet;
-use std::convert::From;
-use std::fs::File;
-use std::io::Read;
-use visitor::OsmVisitor;
-use writer::OsmWriterVisitor;
-
-mod protos;
-mod blob;
-mod visitor;
-mod reader;
-mod writer;
-mod osm;
-
-const INPUT_PATH: &str = "inputs/antarctica-latest.osm.pbf";
-const OUTPUT_PATH: &str = "outputs/coastline.osm.pbf";
+extern crate osm_pbf_iterator;
 
-fn main() {
-    std::fs::create_dir_all("outputs").expect("failed to create output directory");
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use flate2::read::GzDecoder;
+use osm_pbf_iterator::blob::BlobType;
+use osm_pbf_iterator::boundary::PolygonRegion;
+use osm_pbf_iterator::change::{self, ChangeApplier, ReplicationState};
+use osm_pbf_iterator::coastline::CoastlineAssembler;
+use osm_pbf_iterator::combinator::{FilterVisitor, HeaderVisitor};
+use osm_pbf_iterator::extract::{Extract, ExtractStrategy, Region};
+use osm_pbf_iterator::filter::TagFilter;
+use osm_pbf_iterator::geojson::GeoJsonWriter;
+use osm_pbf_iterator::geometry::{BoundingBox, Polygon};
+use osm_pbf_iterator::inspect::{BlobSummary, FileInfo};
+use osm_pbf_iterator::integrity::IntegrityCheck;
+use osm_pbf_iterator::land::LandGenerator;
+use osm_pbf_iterator::merge;
+use osm_pbf_iterator::location::{DenseLocationStore, Location, LocationResolver, MmapLocationStore, NodeLocationStore, SparseLocationStore, WayGeometry, WayGeometryVisitor};
+use osm_pbf_iterator::multipolygon::{Area, AreaVisitor, MultipolygonAssembler};
+use osm_pbf_iterator::osm::{EntityInfo, MemberReference, NANODEGREE_UNIT, NodeReference, OsmEntityType, OsmReader};
+use osm_pbf_iterator::pipeline::ReferencePipeline;
+use osm_pbf_iterator::protos::osm::HeaderBlock;
+use osm_pbf_iterator::reader::{BlobReader, ErrorSummary};
+use osm_pbf_iterator::renumber::Renumber;
+use osm_pbf_iterator::sort::ExternalSort;
+use osm_pbf_iterator::tagstats::TagStatistics;
+use osm_pbf_iterator::visitor::{Flow, OsmVisitor, VisitResult};
+use osm_pbf_iterator::writer::OsmWriterVisitor;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::process;
+use std::usize;
 
-    let mut input_file = File::open(INPUT_PATH).expect("failed to open input file");
-    let mut output_file = File::create(OUTPUT_PATH).expect("failed to create output file");
+type CommandResult = Result<(), Box<Error>>;
+
+fn main() {
+    let matches = App::new(crate_name!())
+        .version(crate_version!())
+        .about("Reads, filters and transforms OpenStreetMap PBF files")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("cat")
+            .about("Concatenates files into one, optionally keeping only some entity types")
+            .arg(Arg::with_name("inputs").required(true).multiple(true))
+            .arg(output_arg())
+            .arg(Arg::with_name("type").short("t").long("type").takes_value(true)
+                .help("The entity types to keep, as any of 'n', 'w' and 'r'"))
+            .arg(no_metadata_arg()))
+        .subcommand(SubCommand::with_name("filter")
+            .about("Keeps the entities matching a tag filter expression, along with everything they reference")
+            .arg(Arg::with_name("input").required(true))
+            .arg(Arg::with_name("expression").required(true).help("A filter such as 'w/highway=primary,secondary'"))
+            .arg(output_arg())
+            .arg(Arg::with_name("omit-referenced").short("R").long("omit-referenced")
+                .help("Keeps only the matching entities, without the entities they reference"))
+            .arg(no_metadata_arg()))
+        .subcommand(SubCommand::with_name("extract")
+            .about("Cuts extracts bounded by a box or by polygons, writing one output per region")
+            .arg(Arg::with_name("input").required(true))
+            .arg(Arg::with_name("bbox").short("b").long("bbox").takes_value(true).allow_hyphen_values(true).conflicts_with("polygon")
+                .required_unless("polygon").help("A box as 'left,bottom,right,top' in degrees"))
+            .arg(Arg::with_name("polygon").short("p").long("polygon").takes_value(true).multiple(true).number_of_values(1)
+                .help("A .poly or GeoJSON boundary file, which can be given several times"))
+            .arg(output_arg().multiple(true).number_of_values(1)
+                .help("The output file, given once for each region in the same order"))
+            .arg(Arg::with_name("strategy").short("s").long("strategy").takes_value(true)
+                .possible_values(&["simple", "complete_ways", "smart"]).default_value("complete_ways"))
+            .arg(no_metadata_arg()))
+        .subcommand(SubCommand::with_name("info")
+            .about("Shows the header, blobs, encoding and contents of a file")
+            .arg(Arg::with_name("input").required(true))
+            .arg(Arg::with_name("blobs").long("blobs").help("Also lists every blob with its block's encoding and contents")))
+        .subcommand(SubCommand::with_name("stats")
+            .about("Counts entities and shows their ID, location and timestamp ranges")
+            .arg(Arg::with_name("input").required(true)))
+        .subcommand(SubCommand::with_name("tags")
+            .about("Counts how often keys and their values are used, per entity type")
+            .arg(Arg::with_name("input").required(true))
+            .arg(Arg::with_name("output").short("o").long("output").takes_value(true)
+                .help("The file to write the statistics to, instead of standard output"))
+            .arg(Arg::with_name("format").short("F").long("format").takes_value(true)
+                .possible_values(&["csv", "json"]).default_value("csv"))
+            .arg(Arg::with_name("top-keys").short("k").long("top-keys").takes_value(true)
+                .help("Only writes this many of the most frequent keys"))
+            .arg(Arg::with_name("top-values").short("n").long("top-values").takes_value(true).default_value("10")
+                .help("The number of most frequent values written per key"))
+            .arg(Arg::with_name("max-values").long("max-values").takes_value(true)
+                .help("Bounds memory by tracking at most about twice this many values per key, making counts approximate beyond it")))
+        .subcommand(SubCommand::with_name("merge")
+            .about("Merges sorted files, keeping the newest version of entities present in several of them")
+            .arg(Arg::with_name("inputs").required(true).multiple(true))
+            .arg(output_arg())
+            .arg(no_metadata_arg()))
+        .subcommand(SubCommand::with_name("sort")
+            .about("Sorts a file by entity type and then ID")
+            .arg(Arg::with_name("input").required(true))
+            .arg(output_arg())
+            .arg(Arg::with_name("run-size").long("run-size").takes_value(true)
+                .help("The number of entities sorted in memory at once before being spilled to a temporary file"))
+            .arg(Arg::with_name("temp-dir").long("temp-dir").takes_value(true)
+                .help("The directory for temporary files, instead of the system's"))
+            .arg(no_metadata_arg()))
+        .subcommand(SubCommand::with_name("renumber")
+            .about("Renumbers nodes, ways and relations to dense ranges of IDs, keeping references consistent")
+            .arg(Arg::with_name("input").required(true))
+            .arg(output_arg())
+            .arg(Arg::with_name("start-node").long("start-node").takes_value(true).allow_hyphen_values(true)
+                .help("The first node ID, 1 by default"))
+            .arg(Arg::with_name("start-way").long("start-way").takes_value(true).allow_hyphen_values(true)
+                .help("The first way ID, 1 by default"))
+            .arg(Arg::with_name("start-relation").long("start-relation").takes_value(true).allow_hyphen_values(true)
+                .help("The first relation ID, 1 by default"))
+            .arg(no_metadata_arg()))
+        .subcommand(SubCommand::with_name("apply-changes")
+            .about("Applies osmChange files to a sorted file, in the order given")
+            .arg(Arg::with_name("input").required(true))
+            .arg(Arg::with_name("changes").required(true).multiple(true)
+                .help("osmChange files, which may be gzipped if named with a .gz extension"))
+            .arg(output_arg())
+            .arg(Arg::with_name("state").short("s").long("state").takes_value(true)
+                .help("The state.txt of the last diff, whose sequence number and timestamp are written to the header"))
+            .arg(no_metadata_arg()))
+        .subcommand(SubCommand::with_name("check")
+            .about("Checks referential integrity, listing entities with missing references, duplicate IDs or invalid geometry")
+            .arg(Arg::with_name("input").required(true))
+            .arg(Arg::with_name("max-problems").short("n").long("max-problems").takes_value(true).default_value("100")
+                .help("The number of problems listed, beyond which they are only counted"))
+            .args(&location_store_args()))
+        .subcommand(SubCommand::with_name("export")
+            .about("Exports tagged entities with their geometries to GeoJSON")
+            .arg(Arg::with_name("input").required(true))
+            .arg(output_arg())
+            .arg(Arg::with_name("filter").short("f").long("filter").takes_value(true)
+                .help("Only exports entities matching this filter expression"))
+            .args(&location_store_args())
+            .arg(Arg::with_name("reuse-locations").long("reuse-locations").requires("location-file")
+                .help("Reads node locations from an existing location file written by an earlier run instead of storing them again")))
+        .subcommand(SubCommand::with_name("coastline")
+            .about("Extracts the coastline, assembles it into rings and generates land polygons")
+            .arg(Arg::with_name("input").required(true))
+            .arg(Arg::with_name("output-dir").short("d").long("output-dir").takes_value(true).default_value("outputs"))
+            .arg(Arg::with_name("filter").short("f").long("filter").takes_value(true).default_value("natural=coastline"))
+            .arg(Arg::with_name("grid").short("g").long("grid").takes_value(true)
+                .help("Splits land polygons into a grid of cells of this size in degrees")))
+        .get_matches();
 
-    let mut node_collector = NodeCollectionVisitor::new();
+    let result = match matches.subcommand() {
+        ("cat", Some(matches)) => cat(matches),
+        ("filter", Some(matches)) => filter(matches),
+        ("extract", Some(matches)) => extract(matches),
+        ("info", Some(matches)) => info(matches),
+        ("stats", Some(matches)) => stats(matches),
+        ("tags", Some(matches)) => tags(matches),
+        ("merge", Some(matches)) => merge(matches),
+        ("sort", Some(matches)) => sort(matches),
+        ("renumber", Some(matches)) => renumber(matches),
+        ("apply-changes", Some(matches)) => apply_changes(matches),
+        ("check", Some(matches)) => check(matches),
+        ("export", Some(matches)) => export(matches),
+        ("coastline", Some(matches)) => coastline(matches),
+        _ => unreachable!("a subcommand is required"),
+    };
 
-    let mut reader = OsmReader::from(BlobReader::from(&mut input_file));
+    if let Err(error) = result {
+        eprintln!("error: {}", error);
+        process::exit(1);
+    }
+}
 
-    println!("collecting coastline nodes");
-    reader.accept(&mut node_collector);
-    println!("collected {} nodes", node_collector.nodes.len());
+fn output_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("output").short("o").long("output").takes_value(true).required(true)
+}
 
-    let mut writer = OsmWriterVisitor::new(&mut output_file, true);
-    reader.accept(&mut CoastlineVisitor { parent: &mut writer, nodes: &node_collector.nodes });
+fn no_metadata_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("no-metadata").long("no-metadata").help("Leaves out versions, timestamps, changesets and users")
 }
 
-pub struct NodeCollectionVisitor {
-    nodes: HashSet<i64>,
+fn location_store_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("dense").long("dense").conflicts_with("location-file")
+            .help("Keeps node locations in an array indexed by ID, which suits large inputs"),
+        Arg::with_name("location-file").long("location-file").takes_value(true)
+            .help("Keeps node locations in a memory mapped file, which suits planet-sized inputs"),
+    ]
 }
 
-impl NodeCollectionVisitor {
-    fn new() -> NodeCollectionVisitor {
-        NodeCollectionVisitor {
-            nodes: HashSet::new()
+/// Creates the node location store chosen on the command line, returning whether it already holds the locations
+/// of the input, which only commands offering `--reuse-locations` allow.
+fn location_store(matches: &ArgMatches) -> Result<(Box<NodeLocationStore>, bool), Box<Error>> {
+    if matches.is_present("dense") {
+        Ok((Box::new(DenseLocationStore::new()), false))
+    } else if let Some(path) = matches.value_of("location-file") {
+        if matches.is_present("reuse-locations") {
+            Ok((Box::new(MmapLocationStore::open(path)?), true))
+        } else {
+            Ok((Box::new(MmapLocationStore::create(path)?), false))
         }
+    } else {
+        Ok((Box::new(SparseLocationStore::new()), false))
     }
 }
 
-fn accepts_tags(tags: &Vec<(String, String)>) -> bool {
-    tags.iter().any(|(k, v)| *k == "natural" && *v == "coastline")
+fn report_errors(path: &str, summary: &ErrorSummary) {
+    for error in &summary.errors {
+        eprintln!("{}: {}", path, error);
+    }
+    if !summary.is_clean() {
+        eprintln!("{}: skipped {} blobs and {} elements", path, summary.skipped_blobs, summary.skipped_elements);
+    }
+}
+
+fn parse_types(text: &str) -> Result<Vec<OsmEntityType>, Box<Error>> {
+    text.chars()
+        .filter(|c| *c != ',')
+        .map(|c| match c {
+            'n' => Ok(OsmEntityType::Node),
+            'w' => Ok(OsmEntityType::Way),
+            'r' => Ok(OsmEntityType::Relation),
+            _ => Err(format!("unknown entity type '{}'", c).into()),
+        })
+        .collect()
+}
+
+fn parse_bbox(text: &str) -> Result<BoundingBox, Box<Error>> {
+    let values = text.split(',')
+        .map(|value| value.trim().parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .map_err(|_| format!("invalid bounding box '{}'", text))?;
+    if values.len() != 4 || values[0] > values[2] || values[1] > values[3] {
+        return Err(format!("invalid bounding box '{}', expected 'left,bottom,right,top'", text).into());
+    }
+    Ok(BoundingBox::new(values[0], values[1], values[2], values[3]))
 }
 
-// TODO: Relation members?
-impl OsmVisitor for NodeCollectionVisitor {
-    fn visit_way(&mut self, _id: i64, nodes: Vec<NodeReference>, tags: Vec<(String, String)>, _info: EntityInfo) -> Result<(), PbfParseError> {
-        if accepts_tags(&tags) {
-            for node in nodes {
-                self.nodes.insert(node.id);
+fn cat(matches: &ArgMatches) -> CommandResult {
+    let types = match matches.value_of("type") {
+        Some(types) => parse_types(types)?,
+        None => vec![OsmEntityType::Node, OsmEntityType::Way, OsmEntityType::Relation],
+    };
+
+    let inputs: Vec<&str> = matches.values_of("inputs").unwrap().collect();
+    // Only the first header is written, which doesn't describe several files appended one after another
+    let concatenated = inputs.len() > 1;
+
+    let mut output = File::create(matches.value_of("output").unwrap())?;
+    let mut writer = OsmWriterVisitor::new(&mut output, !matches.is_present("no-metadata"));
+    for path in inputs {
+        let mut input = File::open(path)?;
+        let mut reader = OsmReader::from(BlobReader::from(&mut input));
+        let mut header = HeaderVisitor::new(&mut writer, |header| {
+            if concatenated {
+                let features = header.take_optional_features().into_iter().filter(|feature| feature != "Sort.Type_then_ID").collect();
+                header.set_optional_features(features);
+                header.clear_bbox();
             }
+        });
+        let mut filter = FilterVisitor::new(&mut header, |entity| types.contains(&entity.entity_type()));
+        report_errors(path, &reader.accept(&mut filter)?);
+    }
+    Ok(())
+}
+
+fn filter(matches: &ArgMatches) -> CommandResult {
+    let path = matches.value_of("input").unwrap();
+    let filter = TagFilter::parse(matches.value_of("expression").unwrap())?;
+
+    let mut input = File::open(path)?;
+    let mut reader = OsmReader::from(BlobReader::from(&mut input));
+    let mut output = File::create(matches.value_of("output").unwrap())?;
+    let mut writer = OsmWriterVisitor::new(&mut output, !matches.is_present("no-metadata"));
+
+    if matches.is_present("omit-referenced") {
+        let mut filter_visitor = FilterVisitor::new(&mut writer, |entity| filter.matches_entity(entity));
+        report_errors(path, &reader.accept(&mut filter_visitor)?);
+    } else {
+        let mut pipeline = ReferencePipeline::new(|entity| filter.matches_entity(entity));
+        let (selection, summary) = pipeline.run(&mut reader, &mut writer)?;
+        println!("wrote {} nodes, {} ways and {} relations", selection.nodes.len(), selection.ways.len(), selection.relations.len());
+        report_errors(path, &summary);
+    }
+    Ok(())
+}
+
+fn extract(matches: &ArgMatches) -> CommandResult {
+    let path = matches.value_of("input").unwrap();
+    let strategy = match matches.value_of("strategy").unwrap() {
+        "simple" => ExtractStrategy::Simple,
+        "smart" => ExtractStrategy::Smart,
+        _ => ExtractStrategy::CompleteWays,
+    };
+
+    let regions: Vec<Box<Region>> = match matches.value_of("bbox") {
+        Some(bbox) => vec![Box::new(parse_bbox(bbox)?)],
+        None => matches.values_of("polygon").unwrap()
+            .map(|path| PolygonRegion::open(path).map(|region| Box::new(region) as Box<Region>))
+            .collect::<Result<_, _>>()?,
+    };
+    let output_paths: Vec<&str> = matches.values_of("output").unwrap().collect();
+    if output_paths.len() != regions.len() {
+        return Err(format!("got {} regions but {} outputs", regions.len(), output_paths.len()).into());
+    }
+
+    let mut input = File::open(path)?;
+    let mut reader = OsmReader::from(BlobReader::from(&mut input));
+    let mut files = output_paths.iter().map(File::create).collect::<Result<Vec<File>, _>>()?;
+    let write_metadata = !matches.is_present("no-metadata");
+    let mut writers: Vec<OsmWriterVisitor> = files.iter_mut().map(|file| OsmWriterVisitor::new(file, write_metadata)).collect();
+    let mut outputs: Vec<&mut OsmVisitor> = writers.iter_mut().map(|writer| writer as &mut OsmVisitor).collect();
+
+    let (selections, summary) = Extract::with_regions(regions, strategy).run(&mut reader, &mut outputs)?;
+    for (output_path, selection) in output_paths.iter().zip(selections.iter()) {
+        println!("{}: wrote {} nodes, {} ways and {} relations", output_path, selection.nodes.len(), selection.ways.len(), selection.relations.len());
+    }
+    report_errors(path, &summary);
+    Ok(())
+}
+
+fn info(matches: &ArgMatches) -> CommandResult {
+    let path = matches.value_of("input").unwrap();
+    let mut input = File::open(path)?;
+    let (info, summary) = FileInfo::read(&mut BlobReader::from(&mut input))?;
+    report_errors(path, &summary);
+
+    println!("file: {} ({} bytes)", path, fs::metadata(path)?.len());
+    match info.header {
+        Some(ref header) => print_header(header),
+        None => println!("header: none"),
+    }
+
+    let data_blobs = info.blobs.iter().filter(|blob| blob.data_type == BlobType::DATA).count();
+    println!("blobs: {} ({} header, {} data), {} bytes stored, {} bytes uncompressed",
+             info.blobs.len(), info.blobs.len() - data_blobs, data_blobs, info.stored_size(), info.raw_size());
+    let compressions: Vec<String> = info.compressions().iter().map(|(name, count)| format!("{} {}", name, count)).collect();
+    println!("compression: {}", compressions.join(", "));
+    println!("granularities: {}", join(&info.granularities()));
+    println!("date granularities: {}", join(&info.date_granularities()));
+    let offsets: Vec<String> = info.offsets().iter().map(|&(lat, lon)| format!("{}/{}", lat, lon)).collect();
+    println!("lat/lon offsets: {}", offsets.join(", "));
+    for (entity_type, range) in &info.entities {
+        println!("{}s: {}, IDs {} to {}", entity_type, range.count, range.min, range.max);
+    }
+    println!("sorted: {} (declared {})", yes_no(info.sorted), yes_no(info.declared_sorted()));
+
+    if matches.is_present("blobs") {
+        for blob in &info.blobs {
+            print_blob(blob);
         }
-        Ok(())
     }
+    Ok(())
 }
 
-pub struct CoastlineVisitor<'a> {
-    parent: &'a mut OsmVisitor,
-    nodes: &'a HashSet<i64>,
+fn print_header(header: &HeaderBlock) {
+    if header.has_bbox() {
+        let bbox = header.get_bbox();
+        let degrees = |nanodegrees: i64| nanodegrees as f64 * NANODEGREE_UNIT;
+        println!("bbox: {},{},{},{}", degrees(bbox.get_left()), degrees(bbox.get_bottom()), degrees(bbox.get_right()), degrees(bbox.get_top()));
+    }
+    println!("required features: {}", header.get_required_features().join(", "));
+    println!("optional features: {}", header.get_optional_features().join(", "));
+    if header.has_writingprogram() {
+        println!("writing program: {}", header.get_writingprogram());
+    }
+    if header.has_source() {
+        println!("source: {}", header.get_source());
+    }
+    if header.has_osmosis_replication_timestamp() {
+        println!("replication timestamp: {}", header.get_osmosis_replication_timestamp());
+    }
+    if header.has_osmosis_replication_sequence_number() {
+        println!("replication sequence number: {}", header.get_osmosis_replication_sequence_number());
+    }
+    if header.has_osmosis_replication_base_url() {
+        println!("replication base url: {}", header.get_osmosis_replication_base_url());
+    }
 }
 
-impl<'a> OsmVisitor for CoastlineVisitor<'a> {
-    fn visit_block(&mut self, _lat_offset: i64, _lon_offset: i64, _granularity: i32, _date_granularity: i32) -> Result<(), PbfParseError> {
-        Ok(())
+fn print_blob(blob: &BlobSummary) {
+    let data_type: String = blob.data_type.into();
+    let layout = &blob.layout;
+    print!("blob {} at {}: {}, {} + {} bytes, {}, {} bytes uncompressed",
+           blob.index, blob.offset, data_type, layout.header_size, layout.data_size, layout.compression.name(), blob.raw_size);
+    if let Some(ref block) = blob.block {
+        print!(", granularity {}, date granularity {}, offsets {}/{}, {} strings, {} groups",
+               block.granularity, block.date_granularity, block.lat_offset, block.lon_offset, block.strings, block.groups);
+        for (entity_type, range) in &block.entities {
+            print!(", {} {}s {} to {}", range.count, entity_type, range.min, range.max);
+        }
     }
+    println!();
+}
 
-    fn end_block(&mut self) -> Result<(), PbfParseError> {
-        self.parent.end_block()
+fn join<T: ToString>(values: &BTreeSet<T>) -> String {
+    values.iter().map(|value| value.to_string()).collect::<Vec<String>>().join(", ")
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value { "yes" } else { "no" }
+}
+
+#[derive(Default)]
+struct EntityStats {
+    counts: BTreeMap<OsmEntityType, (usize, usize)>,
+    id_ranges: BTreeMap<OsmEntityType, (i64, i64)>,
+    bbox: Option<BoundingBox>,
+    timestamps: Option<(i64, i64)>,
+}
+
+impl EntityStats {
+    fn add(&mut self, entity_type: OsmEntityType, id: i64, tagged: bool, info: EntityInfo) {
+        let count = self.counts.entry(entity_type).or_insert((0, 0));
+        count.0 += 1;
+        if tagged {
+            count.1 += 1;
+        }
+        let range = self.id_ranges.entry(entity_type).or_insert((id, id));
+        *range = (range.0.min(id), range.1.max(id));
+        if info.timestamp > 0 {
+            let timestamps = self.timestamps.get_or_insert((info.timestamp, info.timestamp));
+            *timestamps = (timestamps.0.min(info.timestamp), timestamps.1.max(info.timestamp));
+        }
     }
+}
 
-    fn visit_node(&mut self, id: i64, latitude: f64, longitude: f64, tags: Vec<(String, String)>, info: EntityInfo) -> Result<(), PbfParseError> {
-        if self.nodes.contains(&id) {
-            self.parent.visit_node(id, latitude, longitude, tags, info)
-        } else {
-            Ok(())
+impl OsmVisitor for EntityStats {
+    fn visit_node(&mut self, id: i64, latitude: f64, longitude: f64, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+        self.add(OsmEntityType::Node, id, !tags.is_empty(), info);
+        let location = Location::new(latitude, longitude);
+        match self.bbox {
+            Some(ref mut bbox) => bbox.expand(location),
+            None => self.bbox = BoundingBox::from_locations(&[location]),
         }
+        Ok(Flow::Continue)
     }
 
-    fn visit_way(&mut self, id: i64, nodes: Vec<NodeReference>, tags: Vec<(String, String)>, info: EntityInfo) -> Result<(), PbfParseError> {
-        if accepts_tags(&tags) {
-            self.parent.visit_way(id, nodes, tags, info)
-        } else {
-            Ok(())
+    fn visit_way(&mut self, id: i64, _nodes: Vec<NodeReference>, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+        self.add(OsmEntityType::Way, id, !tags.is_empty(), info);
+        Ok(Flow::Continue)
+    }
+
+    fn visit_relation(&mut self, id: i64, _members: Vec<MemberReference>, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+        self.add(OsmEntityType::Relation, id, !tags.is_empty(), info);
+        Ok(Flow::Continue)
+    }
+}
+
+fn stats(matches: &ArgMatches) -> CommandResult {
+    let path = matches.value_of("input").unwrap();
+    let mut input = File::open(path)?;
+    let mut reader = OsmReader::from(BlobReader::from(&mut input));
+    let mut stats = EntityStats::default();
+    report_errors(path, &reader.accept(&mut stats)?);
+
+    for (entity_type, &(count, tagged)) in &stats.counts {
+        let (min_id, max_id) = stats.id_ranges[entity_type];
+        println!("{}s: {} ({} tagged), IDs {} to {}", entity_type, count, tagged, min_id, max_id);
+    }
+    if let Some(bbox) = stats.bbox {
+        println!("node bbox: {},{},{},{}", bbox.left, bbox.bottom, bbox.right, bbox.top);
+    }
+    if let Some((first, last)) = stats.timestamps {
+        println!("timestamps: {} to {}", first, last);
+    }
+    Ok(())
+}
+
+fn tags(matches: &ArgMatches) -> CommandResult {
+    let path = matches.value_of("input").unwrap();
+    let top_keys = parse_count(matches, "top-keys")?.unwrap_or(usize::MAX);
+    let top_values = parse_count(matches, "top-values")?.unwrap_or(0);
+
+    let mut statistics = TagStatistics::new();
+    if let Some(max_values) = parse_count(matches, "max-values")? {
+        statistics.set_max_values(max_values);
+    }
+    let mut input = File::open(path)?;
+    let mut reader = OsmReader::from(BlobReader::from(&mut input));
+    report_errors(path, &reader.accept(&mut statistics)?);
+
+    let stdout = io::stdout();
+    let mut output: Box<Write> = match matches.value_of("output") {
+        Some(output_path) => Box::new(BufWriter::new(File::create(output_path)?)),
+        None => Box::new(stdout.lock()),
+    };
+    match matches.value_of("format") {
+        Some("json") => statistics.write_json(&mut *output, top_keys, top_values)?,
+        _ => statistics.write_csv(&mut *output, top_keys, top_values)?,
+    }
+    output.flush()?;
+    Ok(())
+}
+
+fn parse_count(matches: &ArgMatches, name: &str) -> Result<Option<usize>, Box<Error>> {
+    match matches.value_of(name) {
+        Some(value) => Ok(Some(value.parse().map_err(|_| format!("invalid {} '{}'", name, value))?)),
+        None => Ok(None),
+    }
+}
+
+fn merge(matches: &ArgMatches) -> CommandResult {
+    let mut inputs = matches.values_of("inputs").unwrap().map(File::open).collect::<Result<Vec<File>, _>>()?;
+    let mut output = File::create(matches.value_of("output").unwrap())?;
+    let mut writer = OsmWriterVisitor::new(&mut output, !matches.is_present("no-metadata"));
+    let summary = merge::merge(inputs.iter_mut().collect(), &mut writer)?;
+    println!("wrote {} entities, leaving out {} duplicates and {} older versions", summary.written, summary.duplicates, summary.conflicts);
+    Ok(())
+}
+
+fn sort(matches: &ArgMatches) -> CommandResult {
+    let path = matches.value_of("input").unwrap();
+    let mut sort = ExternalSort::new();
+    if let Some(run_size) = parse_count(matches, "run-size")? {
+        sort.set_run_size(run_size);
+    }
+    if let Some(temp_dir) = matches.value_of("temp-dir") {
+        sort.set_temp_dir(temp_dir);
+    }
+
+    let mut input = File::open(path)?;
+    let mut reader = OsmReader::from(BlobReader::from(&mut input));
+    let mut output = File::create(matches.value_of("output").unwrap())?;
+    let mut writer = OsmWriterVisitor::new(&mut output, !matches.is_present("no-metadata"));
+    let (merged, summary) = sort.run(&mut reader, &mut writer)?;
+    println!("wrote {} entities, leaving out {} older versions", merged.written, merged.conflicts + merged.duplicates);
+    report_errors(path, &summary);
+    Ok(())
+}
+
+fn renumber(matches: &ArgMatches) -> CommandResult {
+    let path = matches.value_of("input").unwrap();
+    let mut renumber = Renumber::new();
+    for &(name, entity_type) in &[("start-node", OsmEntityType::Node), ("start-way", OsmEntityType::Way), ("start-relation", OsmEntityType::Relation)] {
+        if let Some(value) = matches.value_of(name) {
+            renumber.set_start(entity_type, value.parse().map_err(|_| format!("invalid {} '{}'", name, value))?);
         }
     }
 
-    fn visit_relation(&mut self, id: i64, members: Vec<MemberReference>, tags: Vec<(String, String)>, info: EntityInfo) -> Result<(), PbfParseError> {
-        if accepts_tags(&tags) {
-            self.parent.visit_relation(id, members, tags, info)
+    let mut input = File::open(path)?;
+    let mut reader = OsmReader::from(BlobReader::from(&mut input));
+    let mut output = File::create(matches.value_of("output").unwrap())?;
+    let mut writer = OsmWriterVisitor::new(&mut output, !matches.is_present("no-metadata"));
+    let (renumbered, summary) = renumber.run(&mut reader, &mut writer)?;
+    println!("renumbered {} nodes, {} ways and {} relations", renumbered.nodes, renumbered.ways, renumbered.relations);
+    let missing = renumbered.missing_nodes + renumbered.missing_ways + renumbered.missing_relations;
+    if missing > 0 {
+        println!(
+            "gave new IDs to {} missing nodes, {} missing ways and {} missing relations",
+            renumbered.missing_nodes, renumbered.missing_ways, renumbered.missing_relations
+        );
+    }
+    report_errors(path, &summary);
+    Ok(())
+}
+
+fn apply_changes(matches: &ArgMatches) -> CommandResult {
+    let path = matches.value_of("input").unwrap();
+    let mut applier = ChangeApplier::new();
+    for changes_path in matches.values_of("changes").unwrap() {
+        let file = File::open(changes_path)?;
+        let changes = if changes_path.ends_with(".gz") {
+            change::parse_changes(GzDecoder::new(file))?
         } else {
-            Ok(())
-        }
+            change::parse_changes(io::BufReader::new(file))?
+        };
+        applier.add_changes(changes);
+    }
+    if let Some(state_path) = matches.value_of("state") {
+        applier.set_replication_state(ReplicationState::parse(&fs::read_to_string(state_path)?)?);
     }
 
-    fn visit_header(&mut self, block: &HeaderBlock) -> Result<(), PbfParseError> {
-//        println!("found header {:?}", block);
-        self.parent.visit_header(block)
+    let mut input = File::open(path)?;
+    let mut reader = OsmReader::from(BlobReader::from(&mut input));
+    let mut output = File::create(matches.value_of("output").unwrap())?;
+    let mut writer = OsmWriterVisitor::new(&mut output, !matches.is_present("no-metadata"));
+    let (applied, summary) = applier.run(&mut reader, &mut writer)?;
+    println!(
+        "created {}, modified {} and deleted {} entities, skipping {} changes",
+        applied.created, applied.modified, applied.deleted, applied.skipped
+    );
+    report_errors(path, &summary);
+    Ok(())
+}
+
+fn check(matches: &ArgMatches) -> CommandResult {
+    let path = matches.value_of("input").unwrap();
+    // The check has no --reuse-locations, so the store always starts out empty and holds only the checked nodes
+    let (mut store, _) = location_store(matches)?;
+    let mut check = IntegrityCheck::new(&mut *store);
+    if let Some(max_problems) = parse_count(matches, "max-problems")? {
+        check.set_max_problems(max_problems);
     }
 
-    fn end(&mut self) -> Result<(), PbfParseError> {
-        self.parent.end()
+    let mut input = File::open(path)?;
+    let mut reader = OsmReader::from(BlobReader::from(&mut input));
+    let (report, summary) = check.run(&mut reader)?;
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    if report.problems.len() < report.total() {
+        println!("... and {} more", report.total() - report.problems.len());
+    }
+    for (kind, count) in &report.counts {
+        println!("{}: {}", kind, count);
     }
+    report_errors(path, &summary);
+    if !report.is_clean() {
+        return Err(format!("found {} problems", report.total()).into());
+    }
+    println!("no problems found");
+    Ok(())
+}
+
+fn feature_properties<'a>(entity_type: OsmEntityType, id: i64, tags: &'a [(String, String)]) -> Vec<(&'a str, String)> {
+    let mut properties = vec![("@type", entity_type.to_string()), ("@id", id.to_string())];
+    properties.extend(tags.iter().map(|(k, v)| (k.as_str(), v.clone())));
+    properties
+}
 
-    fn handle_error(&mut self, error: &PbfParseError) -> bool {
-        println!("found error {:?}", error);
-        false
+/// Whether a closed way with these tags describes an area rather than a line.
+fn is_area_way(tags: &[(String, String)]) -> bool {
+    const AREA_KEYS: &[&str] = &["building", "landuse", "leisure", "amenity", "natural"];
+    if tags.iter().any(|(k, _)| k == "area") {
+        return tags.iter().any(|(k, v)| k == "area" && v == "yes");
     }
+    tags.iter().any(|(k, v)| AREA_KEYS.contains(&k.as_str()) && !(k == "natural" && v == "coastline"))
 }
 
-pub fn read_message<M: protobuf::Message>(reader: &mut Read, length: usize) -> Result<M, PbfParseError> {
-    let mut buffer = vec!(0u8; length as usize);
-    reader.read_exact(&mut buffer)?;
-    Ok(protobuf::parse_from_bytes(&buffer)?)
+struct PointExporter<'a, 'b: 'a> {
+    writer: &'a mut GeoJsonWriter<'b>,
+    filter: Option<&'a TagFilter>,
+    store: &'a mut NodeLocationStore,
+    store_nodes: bool,
 }
 
-pub fn read_message_bytes<M: protobuf::Message>(buffer: &[u8]) -> Result<M, PbfParseError> {
-    Ok(protobuf::parse_from_bytes(buffer)?)
+impl<'a, 'b> OsmVisitor for PointExporter<'a, 'b> {
+    fn visit_node(&mut self, id: i64, latitude: f64, longitude: f64, tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        let location = Location::new(latitude, longitude);
+        if self.store_nodes {
+            self.store.set(id, location)?;
+        }
+        if !tags.is_empty() && self.filter.map_or(true, |filter| filter.matches(OsmEntityType::Node, &tags)) {
+            self.writer.write_point(location, &feature_properties(OsmEntityType::Node, id, &tags))?;
+        }
+        Ok(Flow::Continue)
+    }
 }
 
-#[derive(Debug)]
-pub enum PbfParseError {
-    Io(std::io::Error),
-    Eof,
-    InvalidHeaderLength(u32),
-    InvalidBodyLength(u32),
-    InvalidMessage(protobuf::ProtobufError),
-    InvalidBlobFormat,
-    InvalidBlobType,
-    MalformedData,
+struct GeometryExporter<'a, 'b: 'a> {
+    writer: &'a mut GeoJsonWriter<'b>,
+    filter: Option<&'a TagFilter>,
 }
 
-impl From<std::io::Error> for PbfParseError {
-    fn from(err: std::io::Error) -> Self {
-        if err.kind() == std::io::ErrorKind::UnexpectedEof {
-            PbfParseError::Eof
+impl<'a, 'b> WayGeometryVisitor for GeometryExporter<'a, 'b> {
+    fn visit_way_geometry(&mut self, way: WayGeometry) -> VisitResult {
+        if way.tags.is_empty() || way.nodes.len() < 2 || !self.filter.map_or(true, |filter| filter.matches(OsmEntityType::Way, &way.tags)) {
+            return Ok(Flow::Continue);
+        }
+        let properties = feature_properties(OsmEntityType::Way, way.id, &way.tags);
+        let locations = way.locations();
+        let closed = locations.len() > 3 && way.nodes[0].0 == way.nodes[way.nodes.len() - 1].0;
+        if closed && is_area_way(&way.tags) {
+            self.writer.write_polygon(&Polygon::new(locations, Vec::new()), &properties)?;
         } else {
-            PbfParseError::Io(err)
+            self.writer.write_line(&locations, &properties)?;
         }
+        Ok(Flow::Continue)
     }
 }
 
-impl From<protobuf::ProtobufError> for PbfParseError {
-    fn from(err: protobuf::ProtobufError) -> Self {
-        PbfParseError::InvalidMessage(err)
+impl<'a, 'b> AreaVisitor for GeometryExporter<'a, 'b> {
+    fn visit_area(&mut self, area: Area) -> VisitResult {
+        if self.filter.map_or(true, |filter| filter.matches(OsmEntityType::Relation, &area.tags)) {
+            let properties = feature_properties(OsmEntityType::Relation, area.relation_id, &area.tags);
+            self.writer.write_multipolygon(&area.polygons, &properties)?;
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+fn export(matches: &ArgMatches) -> CommandResult {
+    let path = matches.value_of("input").unwrap();
+    let filter = match matches.value_of("filter") {
+        Some(expression) => Some(TagFilter::parse(expression)?),
+        None => None,
+    };
+    let (mut store, populated) = location_store(matches)?;
+
+    let mut input = File::open(path)?;
+    let mut reader = OsmReader::from(BlobReader::from(&mut input));
+    let mut output = File::create(matches.value_of("output").unwrap())?;
+    let mut writer = GeoJsonWriter::new(&mut output)?;
+
+    let mut point_exporter = PointExporter { writer: &mut writer, filter: filter.as_ref(), store: &mut *store, store_nodes: !populated };
+    let mut summary = reader.accept(&mut point_exporter)?;
+
+    let mut geometry_exporter = GeometryExporter { writer: &mut writer, filter: filter.as_ref() };
+    {
+        let mut resolver = LocationResolver::from_populated(&mut *store, &mut geometry_exporter);
+        summary.merge(reader.accept(&mut resolver)?);
+    }
+    summary.merge(MultipolygonAssembler::from_populated(&mut *store).run(&mut reader, &mut geometry_exporter)?);
+
+    writer.finish()?;
+    report_errors(path, &summary);
+    Ok(())
+}
+
+fn coastline(matches: &ArgMatches) -> CommandResult {
+    let path = matches.value_of("input").unwrap();
+    let output_dir = Path::new(matches.value_of("output-dir").unwrap());
+    let filter = TagFilter::parse(matches.value_of("filter").unwrap())?;
+    fs::create_dir_all(output_dir)?;
+
+    let extract_path = output_dir.join("coastline.osm.pbf");
+    {
+        let mut input = File::open(path)?;
+        let mut reader = OsmReader::from(BlobReader::from(&mut input));
+        let mut output = File::create(&extract_path)?;
+        let mut writer = OsmWriterVisitor::new(&mut output, true);
+
+        println!("extracting coastline");
+        let mut pipeline = ReferencePipeline::new(|entity| filter.matches_entity(entity));
+        let (selection, summary) = pipeline.run(&mut reader, &mut writer)?;
+        println!("wrote {} nodes, {} ways and {} relations", selection.nodes.len(), selection.ways.len(), selection.relations.len());
+        report_errors(path, &summary);
+    }
+
+    let mut extract = File::open(&extract_path)?;
+    let mut reader = OsmReader::from(BlobReader::from(&mut extract));
+    let mut store = SparseLocationStore::new();
+
+    println!("assembling coastline rings");
+    let (coastline, summary) = CoastlineAssembler::new(&mut store).run(&mut reader)?;
+    println!("found {} rings, {} open chains and {} problems", coastline.rings.len(), coastline.open.len(), coastline.problems.len());
+    report_errors(path, &summary);
+    coastline.write_geojson(&mut File::create(output_dir.join("coastline.geojson"))?)?;
+
+    println!("generating land polygons");
+    let mut generator = LandGenerator::new();
+    if let Some(grid) = matches.value_of("grid") {
+        generator.set_grid_size(grid.parse().map_err(|_| format!("invalid grid size '{}'", grid))?);
     }
+    let land = generator.generate(&coastline);
+    println!("generated {} land polygons, leaving out {} unclosed chains", land.polygons.len(), land.unclosed.len());
+    land.write_geojson(&mut File::create(output_dir.join("land.geojson"))?)?;
+    Ok(())
 }
@@ -0,0 +1,139 @@
+use ::PbfParseError;
+use osm::{Entity, EntityIterator, OsmEntityType};
+use protos::osm::{HeaderBBox, HeaderBlock};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{Read, Seek};
+use visitor::{Flow, OsmVisitor};
+
+/// What a merge did with the entities of its inputs.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct MergeSummary {
+    pub written: usize,
+    /// Copies of an entity found in another input with the same version, which were left out
+    pub duplicates: usize,
+    /// Copies of an entity found in another input with a different version, of which only the newest was written
+    pub conflicts: usize,
+}
+
+/// Merges files sorted by type and then ID, such as overlapping regional extracts, into a single sorted output.
+///
+/// The inputs are read side by side, one block at a time each, so memory use doesn't grow with their size. An
+/// entity present in several inputs is written once, picking the highest version, or the latest timestamp if the
+/// versions are equal. The output header is that of the first input, keeping its writing program, source and
+/// replication fields, but declares the union of the inputs' features and covers the union of their bounding
+/// boxes, if every input has one.
+///
+/// Fails with `PbfParseError::MalformedData` if an input turns out not to be sorted.
+pub fn merge<T: Read + Seek>(inputs: Vec<&mut T>, output: &mut OsmVisitor) -> Result<MergeSummary, PbfParseError> {
+    let mut iterators = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        iterators.push(EntityIterator::new(input)?);
+    }
+    let header = merge_headers(&iterators.iter().map(|iterator| iterator.header()).collect::<Vec<_>>());
+    let mut summary = MergeSummary::default();
+    if output.visit_header(&header)? == Flow::Stop {
+        output.end()?;
+        return Ok(summary);
+    }
+
+    let mut heads: Vec<Option<Entity>> = Vec::with_capacity(iterators.len());
+    let mut queue = BinaryHeap::new();
+    for (index, iterator) in iterators.iter_mut().enumerate() {
+        let head = next_entity(iterator)?;
+        if let Some(ref entity) = head {
+            queue.push(Reverse((key(entity), index)));
+        }
+        heads.push(head);
+    }
+
+    while let Some(Reverse((current, first))) = queue.pop() {
+        let mut sources = vec![first];
+        while queue.peek().map_or(false, |&Reverse((next, _))| next == current) {
+            let Reverse((_, index)) = queue.pop().unwrap();
+            sources.push(index);
+        }
+
+        let mut chosen: Option<Entity> = None;
+        for &index in &sources {
+            let entity = heads[index].take().expect("queued inputs have a head");
+            chosen = Some(match chosen {
+                None => entity,
+                Some(previous) => {
+                    let (previous_info, info) = (previous.info(), entity.info());
+                    if previous_info.version == info.version {
+                        summary.duplicates += 1;
+                    } else {
+                        summary.conflicts += 1;
+                    }
+                    if (info.version, info.timestamp) > (previous_info.version, previous_info.timestamp) { entity } else { previous }
+                }
+            });
+
+            let next = next_entity(&mut iterators[index])?;
+            if let Some(ref entity) = next {
+                let next_key = key(entity);
+                if next_key <= current {
+                    return Err(PbfParseError::MalformedData(format!(
+                        "input {} is not sorted: {} {} follows {} {}", index, next_key.0, next_key.1, current.0, current.1
+                    )));
+                }
+                queue.push(Reverse((next_key, index)));
+            }
+            heads[index] = next;
+        }
+
+        summary.written += 1;
+        if chosen.expect("at least one input has the entity").visit(output)? == Flow::Stop {
+            break;
+        }
+    }
+    output.end()?;
+    Ok(summary)
+}
+
+fn next_entity<T: Read + Seek>(iterator: &mut EntityIterator<T>) -> Result<Option<Entity>, PbfParseError> {
+    match iterator.next() {
+        Some(entity) => entity.map(Some),
+        None => Ok(None),
+    }
+}
+
+fn key(entity: &Entity) -> (OsmEntityType, i64) {
+    (entity.entity_type(), entity.id())
+}
+
+fn merge_headers(headers: &[Option<&HeaderBlock>]) -> HeaderBlock {
+    let mut present = headers.iter().filter_map(|header| *header);
+    let mut merged = present.next().cloned().unwrap_or_else(HeaderBlock::new);
+    for header in present {
+        for feature in header.get_required_features() {
+            if !merged.get_required_features().contains(feature) {
+                merged.mut_required_features().push(feature.clone());
+            }
+        }
+        for feature in header.get_optional_features() {
+            if !merged.get_optional_features().contains(feature) {
+                merged.mut_optional_features().push(feature.clone());
+            }
+        }
+    }
+    if !merged.get_optional_features().iter().any(|feature| feature == "Sort.Type_then_ID") {
+        merged.mut_optional_features().push("Sort.Type_then_ID".to_string());
+    }
+
+    let bboxes: Vec<&HeaderBBox> = headers.iter()
+        .filter_map(|header| header.and_then(|header| if header.has_bbox() { Some(header.get_bbox()) } else { None }))
+        .collect();
+    if !bboxes.is_empty() && bboxes.len() == headers.len() {
+        let mut bbox = HeaderBBox::new();
+        bbox.set_left(bboxes.iter().map(|bbox| bbox.get_left()).min().unwrap());
+        bbox.set_bottom(bboxes.iter().map(|bbox| bbox.get_bottom()).min().unwrap());
+        bbox.set_right(bboxes.iter().map(|bbox| bbox.get_right()).max().unwrap());
+        bbox.set_top(bboxes.iter().map(|bbox| bbox.get_top()).max().unwrap());
+        merged.set_bbox(bbox);
+    } else {
+        merged.clear_bbox();
+    }
+    merged
+}
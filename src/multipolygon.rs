@@ -0,0 +1,240 @@
+use ::PbfParseError;
+use geometry::{Chain, find_self_intersection, join_chains, Polygon, ring_contains, signed_area};
+use idset::IdSet;
+use location::{Location, LocationResolver, NodeLocationStore, WayGeometry, WayGeometryVisitor};
+use osm::{EntityInfo, MemberReference, OsmEntityType, OsmReader};
+use reader::ErrorSummary;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+use visitor::{ErrorPolicy, Flow, OsmVisitor, VisitResult};
+
+/// A polygon area assembled from a multipolygon or boundary relation.
+#[derive(Debug, Clone)]
+pub struct Area {
+    pub relation_id: i64,
+    pub tags: Vec<(String, String)>,
+    pub info: EntityInfo,
+    pub polygons: Vec<Polygon>,
+}
+
+/// A problem found while assembling the area of a relation. Rings affected by a problem are left out of the area.
+#[derive(Debug, Clone)]
+pub enum AreaProblem {
+    /// A member way is not present in the file
+    MissingWay { way_id: i64 },
+    /// Some nodes of a member way are not present in the node location store
+    MissingNodes { way_id: i64, node_ids: Vec<i64> },
+    /// The member ways could not be joined into a closed ring
+    UnclosedRing { ways: Vec<i64>, start_node: i64, end_node: i64, end: Location },
+    /// A ring crosses or touches itself
+    SelfIntersection { ways: Vec<i64>, location: Location },
+    /// A member way's role does not match the ring it was found to be part of
+    RoleMismatch { way_id: i64, role: String, expected: &'static str },
+}
+
+pub trait AreaVisitor {
+    fn visit_area(&mut self, area: Area) -> VisitResult;
+
+    fn visit_problem(&mut self, _relation_id: i64, _problem: AreaProblem) -> Result<(), PbfParseError> {
+        Ok(())
+    }
+
+    fn handle_error(&mut self, _error: &PbfParseError) -> ErrorPolicy {
+        ErrorPolicy::SkipBlob
+    }
+}
+
+/// Assembles areas from `type=multipolygon` and `type=boundary` relations. The first pass collects the relations
+/// and the second resolves the geometry of their member ways through the node location store, after which member
+/// ways are joined into rings, which are nested into polygons by containment.
+pub struct MultipolygonAssembler<'a> {
+    store: &'a mut NodeLocationStore,
+    store_nodes: bool,
+}
+
+impl<'a> MultipolygonAssembler<'a> {
+    pub fn new(store: &'a mut NodeLocationStore) -> MultipolygonAssembler<'a> {
+        MultipolygonAssembler { store, store_nodes: true }
+    }
+
+    /// Resolves member ways against a store that has already been populated, such as by an earlier pass or a
+    /// reopened location file, without adding to it.
+    pub fn from_populated(store: &'a mut NodeLocationStore) -> MultipolygonAssembler<'a> {
+        MultipolygonAssembler { store, store_nodes: false }
+    }
+
+    pub fn run<T: Read + Seek>(&mut self, reader: &mut OsmReader<T>, visitor: &mut AreaVisitor) -> Result<ErrorSummary, PbfParseError> {
+        let mut relation_collector = AreaRelationCollector { relations: Vec::new(), ways: IdSet::new(), visitor };
+        let mut summary = reader.accept(&mut relation_collector)?;
+        let AreaRelationCollector { relations, ways, visitor } = relation_collector;
+
+        if relations.is_empty() {
+            return Ok(summary);
+        }
+
+        let mut way_collector = MemberWayCollector { ways: &ways, geometries: HashMap::new(), visitor };
+        {
+            let mut resolver = if self.store_nodes {
+                LocationResolver::new(self.store, &mut way_collector)
+            } else {
+                LocationResolver::from_populated(self.store, &mut way_collector)
+            };
+            summary.merge(reader.accept(&mut resolver)?);
+        }
+        let MemberWayCollector { geometries, visitor, .. } = way_collector;
+
+        for relation in relations {
+            if assemble(relation, &geometries, visitor)? == Flow::Stop {
+                break;
+            }
+        }
+        Ok(summary)
+    }
+}
+
+pub fn is_area_relation(tags: &[(String, String)]) -> bool {
+    tags.iter().any(|(k, v)| k == "type" && (v == "multipolygon" || v == "boundary"))
+}
+
+struct AreaRelation {
+    id: i64,
+    tags: Vec<(String, String)>,
+    info: EntityInfo,
+    members: Vec<MemberReference>,
+}
+
+struct AreaRelationCollector<'a> {
+    relations: Vec<AreaRelation>,
+    ways: IdSet,
+    visitor: &'a mut AreaVisitor,
+}
+
+impl<'a> OsmVisitor for AreaRelationCollector<'a> {
+    fn visit_relation(&mut self, id: i64, members: Vec<MemberReference>, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+        if is_area_relation(&tags) {
+            let members: Vec<MemberReference> = members.into_iter()
+                .filter(|member| member.entity_type == OsmEntityType::Way)
+                .collect();
+            self.ways.extend(members.iter().map(|member| member.id));
+            self.relations.push(AreaRelation { id, tags, info, members });
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn handle_error(&mut self, error: &PbfParseError) -> ErrorPolicy {
+        self.visitor.handle_error(error)
+    }
+}
+
+struct MemberWayCollector<'a> {
+    ways: &'a IdSet,
+    geometries: HashMap<i64, WayGeometry>,
+    visitor: &'a mut AreaVisitor,
+}
+
+impl<'a> WayGeometryVisitor for MemberWayCollector<'a> {
+    fn visit_way_geometry(&mut self, mut way: WayGeometry) -> VisitResult {
+        if self.ways.contains(way.id) {
+            way.tags = Vec::new();
+            self.geometries.insert(way.id, way);
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn handle_error(&mut self, error: &PbfParseError) -> ErrorPolicy {
+        self.visitor.handle_error(error)
+    }
+}
+
+struct Ring {
+    chain: Chain,
+    locations: Vec<Location>,
+    area: f64,
+    parent: Option<usize>,
+    depth: usize,
+}
+
+fn assemble(relation: AreaRelation, geometries: &HashMap<i64, WayGeometry>, visitor: &mut AreaVisitor) -> VisitResult {
+    let mut chains = Vec::new();
+    let mut roles: HashMap<i64, &str> = HashMap::new();
+    for member in &relation.members {
+        match geometries.get(&member.id) {
+            Some(way) => {
+                // Joining only the nodes that were found would give wrong rings, so the way is left out and its
+                // ring is reported as unclosed
+                if !way.is_complete() {
+                    visitor.visit_problem(relation.id, AreaProblem::MissingNodes { way_id: way.id, node_ids: way.missing.clone() })?;
+                    continue;
+                }
+                roles.insert(way.id, &member.role);
+                chains.push(Chain::from_way(way.id, way.nodes.clone()));
+            }
+            None => visitor.visit_problem(relation.id, AreaProblem::MissingWay { way_id: member.id })?,
+        }
+    }
+
+    let mut rings: Vec<Ring> = Vec::new();
+    for chain in join_chains(chains, true) {
+        if !chain.is_closed() {
+            let problem = AreaProblem::UnclosedRing {
+                ways: chain.way_ids(),
+                start_node: chain.first_node(),
+                end_node: chain.last_node(),
+                end: chain.nodes[chain.nodes.len() - 1].1,
+            };
+            visitor.visit_problem(relation.id, problem)?;
+            continue;
+        }
+        let locations = chain.locations();
+        if let Some(location) = find_self_intersection(&locations) {
+            visitor.visit_problem(relation.id, AreaProblem::SelfIntersection { ways: chain.way_ids(), location })?;
+            continue;
+        }
+        let area = signed_area(&locations).abs();
+        rings.push(Ring { chain, locations, area, parent: None, depth: 0 });
+    }
+
+    // Nest each ring inside the smallest larger ring containing it: even depths are outer rings, odd depths holes
+    rings.sort_by(|a, b| b.area.partial_cmp(&a.area).unwrap_or(Ordering::Equal));
+    for index in 0..rings.len() {
+        // Rings often share nodes, so probe with the middle of a segment rather than a node
+        let (a, b) = (rings[index].locations[0], rings[index].locations[1]);
+        let probe = Location::new((a.latitude + b.latitude) / 2.0, (a.longitude + b.longitude) / 2.0);
+        let parent = (0..index).rev().find(|candidate| {
+            let candidate = &rings[*candidate];
+            candidate.area > rings[index].area && ring_contains(&candidate.locations, probe)
+        });
+        if let Some(parent) = parent {
+            rings[index].parent = Some(parent);
+            rings[index].depth = rings[parent].depth + 1;
+        }
+    }
+
+    for ring in &rings {
+        let expected = if ring.depth % 2 == 0 { "outer" } else { "inner" };
+        for &(way_id, _) in &ring.chain.ways {
+            let role = roles.get(&way_id).cloned().unwrap_or("");
+            // An empty role is commonly used for outer rings
+            if role != expected && !(role.is_empty() && expected == "outer") {
+                visitor.visit_problem(relation.id, AreaProblem::RoleMismatch { way_id, role: role.to_string(), expected })?;
+            }
+        }
+    }
+
+    let mut polygons = Vec::new();
+    for (index, ring) in rings.iter().enumerate() {
+        if ring.depth % 2 == 0 {
+            let interiors = rings.iter()
+                .filter(|inner| inner.parent == Some(index))
+                .map(|inner| inner.locations.clone())
+                .collect();
+            polygons.push(Polygon::new(ring.locations.clone(), interiors));
+        }
+    }
+
+    if polygons.is_empty() {
+        return Ok(Flow::Continue);
+    }
+    visitor.visit_area(Area { relation_id: relation.id, tags: relation.tags, info: relation.info, polygons })
+}
@@ -1,108 +1,297 @@
 use ::PbfParseError;
 use blob::{Blob, BlobType};
 use protos::osm::{DenseNodes, HeaderBlock, Info, Node, PrimitiveBlock, PrimitiveGroup, Relation, Relation_MemberType, StringTable, Way};
-use reader::BlobReader;
-use std::io::{Read, Seek};
+use reader::{BlobReader, ErrorSummary};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom};
 use std::str;
-use visitor::{BlobVisitor, OsmVisitor};
+use visitor::{BlobVisitor, ErrorPolicy, Flow, OsmVisitor, VisitResult};
 
 pub const NANODEGREE_UNIT: f64 = 1e-9;
 
 pub struct OsmReader<'a, T: 'a + Read + Seek> {
     reader: BlobReader<'a, T>,
+    string_policy: InvalidStringPolicy,
 }
 
 impl<'a, T: 'a + Read + Seek> OsmReader<'a, T> {
     pub fn from(reader: BlobReader<'a, T>) -> OsmReader<'a, T> {
-        OsmReader { reader }
+        OsmReader { reader, string_policy: InvalidStringPolicy::Lossy }
     }
 
-    pub fn accept(&mut self, visitor: &mut OsmVisitor) {
-        self.reader.accept(&mut OsmBlobVisitor::new(visitor));
+    pub fn set_invalid_string_policy(&mut self, policy: InvalidStringPolicy) {
+        self.string_policy = policy;
+    }
+
+    /// Runs one full pass over the file for each visitor, in order, stopping at the first aborted pass.
+    pub fn accept_sequence(&mut self, visitors: &mut [&mut OsmVisitor]) -> Result<Vec<ErrorSummary>, PbfParseError> {
+        let mut summaries = Vec::with_capacity(visitors.len());
+        for visitor in visitors.iter_mut() {
+            summaries.push(self.accept(*visitor)?);
+        }
+        Ok(summaries)
+    }
+
+    /// Visits every entity in the file, returning the errors that were skipped according to the visitor's
+    /// `ErrorPolicy`, or the error that caused the traversal to be aborted.
+    pub fn accept(&mut self, visitor: &mut OsmVisitor) -> Result<ErrorSummary, PbfParseError> {
+        let mut blob_visitor = OsmBlobVisitor::new(visitor, self.string_policy);
+        let mut summary = self.reader.accept(&mut blob_visitor)?;
+        summary.merge(blob_visitor.summary);
+        Ok(summary)
+    }
+}
+
+/// Determines how string table entries that are not valid UTF-8 are decoded.
+/// Entries always keep their position in the table, so later indices are unaffected.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum InvalidStringPolicy {
+    /// Replace invalid sequences with U+FFFD
+    Lossy,
+    /// Decode invalid entries as empty strings, leaving the original bytes available through `BlockStrings::get_raw`.
+    /// Visitors can tell these apart from genuinely empty strings with `BlockStrings::is_valid`.
+    Raw,
+    /// Fail the whole block with `PbfParseError::MalformedData`
+    Error,
+}
+
+/// Reads the entities of a file one block at a time, for consumers that pull entities from several files at once
+/// rather than having them pushed to a visitor. Unlike with `OsmReader`, nothing is skipped: the first error is
+/// returned and ends the iteration.
+pub struct EntityIterator<'a, T: 'a + Read + Seek> {
+    reader: &'a mut T,
+    length: u64,
+    header: Option<HeaderBlock>,
+    entities: VecDeque<Entity>,
+    blob_index: usize,
+    failed: bool,
+}
+
+impl<'a, T: 'a + Read + Seek> EntityIterator<'a, T> {
+    /// Starts reading from the beginning of the file, reading ahead to the first data block so that the header
+    /// is available.
+    pub fn new(reader: &'a mut T) -> Result<EntityIterator<'a, T>, PbfParseError> {
+        let length = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        let mut iterator = EntityIterator {
+            reader,
+            length,
+            header: None,
+            entities: VecDeque::new(),
+            blob_index: 0,
+            failed: false,
+        };
+        iterator.fill()?;
+        Ok(iterator)
+    }
+
+    /// The header of the file, if it has one before its first data block.
+    pub fn header(&self) -> Option<&HeaderBlock> {
+        self.header.as_ref()
+    }
+
+    /// Reads blobs until at least one entity is buffered or the file ends.
+    fn fill(&mut self) -> Result<(), PbfParseError> {
+        while self.entities.is_empty() {
+            let offset = self.reader.seek(SeekFrom::Current(0))?;
+            if offset >= self.length {
+                break;
+            }
+            let index = self.blob_index;
+            self.blob_index += 1;
+
+            let blob = Blob::parse(self.reader).map_err(|e| e.in_blob(index, offset))?;
+            let mut buffer = EntityBuffer { header: &mut self.header, entities: &mut self.entities };
+            OsmBlobVisitor::new(&mut buffer, InvalidStringPolicy::Lossy).visit_blob(&blob).map_err(|e| e.in_blob(index, offset))?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, T: 'a + Read + Seek> Iterator for EntityIterator<'a, T> {
+    type Item = Result<Entity, PbfParseError>;
+
+    fn next(&mut self) -> Option<Result<Entity, PbfParseError>> {
+        if self.failed {
+            return None;
+        }
+        if let Err(e) = self.fill() {
+            self.failed = true;
+            return Some(Err(e));
+        }
+        self.entities.pop_front().map(Ok)
+    }
+}
+
+struct EntityBuffer<'a> {
+    header: &'a mut Option<HeaderBlock>,
+    entities: &'a mut VecDeque<Entity>,
+}
+
+impl<'a> OsmVisitor for EntityBuffer<'a> {
+    fn visit_header(&mut self, block: &HeaderBlock) -> VisitResult {
+        if self.header.is_none() {
+            *self.header = Some(block.clone());
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn visit_node(&mut self, id: i64, latitude: f64, longitude: f64, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+        self.entities.push_back(Entity::Node { id, latitude, longitude, tags, info });
+        Ok(Flow::Continue)
+    }
+
+    fn visit_way(&mut self, id: i64, nodes: Vec<NodeReference>, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+        self.entities.push_back(Entity::Way { id, nodes, tags, info });
+        Ok(Flow::Continue)
+    }
+
+    fn visit_relation(&mut self, id: i64, members: Vec<MemberReference>, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+        self.entities.push_back(Entity::Relation { id, members, tags, info });
+        Ok(Flow::Continue)
+    }
+
+    fn handle_error(&mut self, _error: &PbfParseError) -> ErrorPolicy {
+        ErrorPolicy::Abort
     }
 }
 
 struct OsmBlobVisitor<'a> {
     delegate: &'a mut OsmVisitor,
+    string_policy: InvalidStringPolicy,
+    summary: ErrorSummary,
+    /// The policy already chosen by the delegate for an element error that is propagating up to the blob reader
+    pending_policy: Option<ErrorPolicy>,
 }
 
 impl<'a> OsmBlobVisitor<'a> {
-    fn new(delegate: &'a mut OsmVisitor) -> OsmBlobVisitor<'a> {
-        OsmBlobVisitor { delegate }
+    fn new(delegate: &'a mut OsmVisitor, string_policy: InvalidStringPolicy) -> OsmBlobVisitor<'a> {
+        OsmBlobVisitor { delegate, string_policy, summary: ErrorSummary::default(), pending_policy: None }
+    }
+
+    fn handle_element_error(&mut self, error: PbfParseError) -> Result<(), PbfParseError> {
+        match self.delegate.handle_error(&error) {
+            ErrorPolicy::SkipElement => {
+                self.summary.skip_element(error);
+                Ok(())
+            }
+            policy => {
+                self.pending_policy = Some(policy);
+                Err(error)
+            }
+        }
     }
 
-    fn visit_group(&mut self, parser: &OsmBlockParser, group: &PrimitiveGroup) -> Result<(), PbfParseError> {
-        self.delegate.visit_group()?;
+    fn visit_group(&mut self, parser: &OsmBlockParser, group: &PrimitiveGroup) -> VisitResult {
+        if self.delegate.visit_group()? == Flow::Stop {
+            return Ok(Flow::Stop);
+        }
         let nodes = group.get_nodes();
         let ways = group.get_ways();
         let relations = group.get_relations();
-        if !nodes.is_empty() {
-            self.visit_nodes(parser, nodes)?;
+        let flow = if !nodes.is_empty() {
+            self.visit_nodes(parser, nodes)?
         } else if !ways.is_empty() {
-            self.visit_ways(parser, ways)?;
+            self.visit_ways(parser, ways)?
         } else if !relations.is_empty() {
-            self.visit_relations(parser, relations)?;
+            self.visit_relations(parser, relations)?
         } else if group.has_dense() {
-            self.visit_dense_nodes(parser, group.get_dense())?;
+            self.visit_dense_nodes(parser, group.get_dense())?
+        } else {
+            Flow::Continue
+        };
+        if flow == Flow::Stop {
+            return Ok(Flow::Stop);
         }
         self.delegate.end_group()?;
-        Ok(())
+        Ok(Flow::Continue)
     }
 
-    fn visit_nodes(&mut self, parser: &OsmBlockParser, nodes: &[Node]) -> Result<(), PbfParseError> {
+    fn visit_nodes(&mut self, parser: &OsmBlockParser, nodes: &[Node]) -> VisitResult {
         for node in nodes {
-            let latitude = parser.get_lat(node.get_lat());
-            let longitude = parser.get_lon(node.get_lon());
-            let tags = parser.parse_tags(node.get_keys(), node.get_vals());
-            let info = parser.parse_info(node.get_info());
-            self.delegate.visit_node(node.get_id(), latitude, longitude, tags, info)?;
+            match self.visit_node(parser, node) {
+                Ok(Flow::Stop) => return Ok(Flow::Stop),
+                Ok(Flow::Continue) => (),
+                Err(e) => self.handle_element_error(e.in_element(OsmEntityType::Node, node.get_id()))?,
+            }
         }
-        Ok(())
+        Ok(Flow::Continue)
     }
 
-    fn visit_ways(&mut self, parser: &OsmBlockParser, ways: &[Way]) -> Result<(), PbfParseError> {
-        for way in ways {
-            let tags = parser.parse_tags(way.get_keys(), way.get_vals());
+    fn visit_node(&mut self, parser: &OsmBlockParser, node: &Node) -> VisitResult {
+        let latitude = parser.get_lat(node.get_lat());
+        let longitude = parser.get_lon(node.get_lon());
+        let tags = parser.parse_tags(node.get_keys(), node.get_vals())?;
+        let info = parser.parse_info(node.get_info());
+        self.delegate.visit_node(node.get_id(), latitude, longitude, tags, info)
+    }
 
-            let mut nodes: Vec<NodeReference> = Vec::with_capacity(way.get_refs().len());
-            let mut current_node_id: i64 = 0;
-            for off_id in way.get_refs().iter() {
-                current_node_id += *off_id;
-                nodes.push(NodeReference { id: current_node_id });
+    fn visit_ways(&mut self, parser: &OsmBlockParser, ways: &[Way]) -> VisitResult {
+        for way in ways {
+            match self.visit_way(parser, way) {
+                Ok(Flow::Stop) => return Ok(Flow::Stop),
+                Ok(Flow::Continue) => (),
+                Err(e) => self.handle_element_error(e.in_element(OsmEntityType::Way, way.get_id()))?,
             }
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn visit_way(&mut self, parser: &OsmBlockParser, way: &Way) -> VisitResult {
+        let tags = parser.parse_tags(way.get_keys(), way.get_vals())?;
 
-            let info = parser.parse_info(way.get_info());
-            self.delegate.visit_way(way.get_id(), nodes, tags, info)?;
+        let mut nodes: Vec<NodeReference> = Vec::with_capacity(way.get_refs().len());
+        let mut current_node_id: i64 = 0;
+        for off_id in way.get_refs().iter() {
+            current_node_id += *off_id;
+            nodes.push(NodeReference { id: current_node_id });
         }
 
-        Ok(())
+        let info = parser.parse_info(way.get_info());
+        self.delegate.visit_way(way.get_id(), nodes, tags, info)
     }
 
-    fn visit_relations(&mut self, parser: &OsmBlockParser, relations: &[Relation]) -> Result<(), PbfParseError> {
+    fn visit_relations(&mut self, parser: &OsmBlockParser, relations: &[Relation]) -> VisitResult {
         for relation in relations {
-            let tags = parser.parse_tags(relation.get_keys(), relation.get_vals());
-
-            let types = relation.get_types();
-            let roles = relation.get_roles_sid();
+            match self.visit_relation(parser, relation) {
+                Ok(Flow::Stop) => return Ok(Flow::Stop),
+                Ok(Flow::Continue) => (),
+                Err(e) => self.handle_element_error(e.in_element(OsmEntityType::Relation, relation.get_id()))?,
+            }
+        }
+        Ok(Flow::Continue)
+    }
 
-            let mut members: Vec<MemberReference> = Vec::with_capacity(relation.get_memids().len());
-            let mut current_member_id: i64 = 0;
+    fn visit_relation(&mut self, parser: &OsmBlockParser, relation: &Relation) -> VisitResult {
+        let tags = parser.parse_tags(relation.get_keys(), relation.get_vals())?;
+
+        let member_ids = relation.get_memids();
+        let types = relation.get_types();
+        let roles = relation.get_roles_sid();
+        if types.len() != member_ids.len() || roles.len() != member_ids.len() {
+            return Err(PbfParseError::MalformedData(format!(
+                "{} member ids but {} types and {} roles",
+                member_ids.len(), types.len(), roles.len()
+            )));
+        }
 
-            for (i, off_id) in relation.get_memids().iter().enumerate() {
-                current_member_id += *off_id;
-                let entity_type = OsmEntityType::from(types[i]);
-                let role_sid = roles[i];
-                members.push(MemberReference { id: current_member_id, entity_type, role_sid });
-            }
+        let mut members: Vec<MemberReference> = Vec::with_capacity(member_ids.len());
+        let mut current_member_id: i64 = 0;
 
-            let info = parser.parse_info(relation.get_info());
-            self.delegate.visit_relation(relation.get_id(), members, tags, info)?;
+        for (i, off_id) in member_ids.iter().enumerate() {
+            current_member_id += *off_id;
+            let entity_type = OsmEntityType::from(types[i]);
+            let role = parser.get_string(roles[i] as i64)?;
+            members.push(MemberReference { id: current_member_id, entity_type, role });
         }
 
-        Ok(())
+        let info = parser.parse_info(relation.get_info());
+        self.delegate.visit_relation(relation.get_id(), members, tags, info)
     }
 
-    fn visit_dense_nodes(&mut self, parser: &OsmBlockParser, dense: &DenseNodes) -> Result<(), PbfParseError> {
+    fn visit_dense_nodes(&mut self, parser: &OsmBlockParser, dense: &DenseNodes) -> VisitResult {
         let info = dense.get_denseinfo();
 
         let mut current_id: i64 = 0;
@@ -118,6 +307,13 @@ impl<'a> OsmBlobVisitor<'a> {
         let lons = dense.get_lon();
         let raw_tags = dense.get_keys_vals();
 
+        if lats.len() != ids.len() || lons.len() != ids.len() {
+            return Err(PbfParseError::MalformedData(format!(
+                "dense nodes have {} ids but {} latitudes and {} longitudes",
+                ids.len(), lats.len(), lons.len()
+            )));
+        }
+
         let mut raw_tag_index = 0;
 
         let versions = info.get_version();
@@ -127,33 +323,30 @@ impl<'a> OsmBlobVisitor<'a> {
         let user_sids = info.get_user_sid();
         let visibility = info.get_visible();
 
+        // Dense info is optional as a whole, but if present it must cover every node
+        let has_info = !versions.is_empty();
+        let info_lengths = [versions.len(), timestamps.len(), changesets.len(), uids.len(), user_sids.len()];
+        if has_info && info_lengths.iter().any(|len| *len != ids.len()) {
+            return Err(PbfParseError::MalformedData(format!(
+                "dense info does not cover all {} dense nodes", ids.len()
+            )));
+        }
+
         for i in 0..ids.len() {
             current_id += ids[i];
             current_lat += lats[i];
             current_lon += lons[i];
-            current_timestamp += timestamps[i];
-            current_changeset += changesets[i];
-            current_uid += uids[i];
-            current_user_sid += user_sids[i];
-
-            let mut tags: Vec<(String, String)> = Vec::new();
-            while raw_tag_index < raw_tags.len() {
-                if raw_tags[raw_tag_index] == 0 {
-                    raw_tag_index += 1;
-                    break;
-                }
-                // If we haven't reached the end of this node's tags we should always have a key and value available
-                if raw_tag_index + 2 > raw_tags.len() {
-                    return Err(PbfParseError::MalformedData);
-                }
-                let key_id = raw_tags[raw_tag_index];
-                let val_id = raw_tags[raw_tag_index + 1];
-                tags.push((parser.get_string(key_id), parser.get_string(val_id)));
-                raw_tag_index += 2;
+            if has_info {
+                current_timestamp += timestamps[i];
+                current_changeset += changesets[i];
+                current_uid += uids[i];
+                current_user_sid += user_sids[i];
             }
 
+            let tags = parser.parse_dense_tags(raw_tags, &mut raw_tag_index);
+
             let info = EntityInfo {
-                version: versions[i],
+                version: if has_info { versions[i] } else { -1 },
                 timestamp: parser.get_time(current_timestamp),
                 changeset: current_changeset,
                 uid: current_uid,
@@ -162,41 +355,56 @@ impl<'a> OsmBlobVisitor<'a> {
                 visible: if i < visibility.len() { visibility[i] } else { true },
             };
 
-            self.delegate.visit_node(current_id, parser.get_lat(current_lat), parser.get_lon(current_lon), tags, info)?;
+            let (latitude, longitude) = (parser.get_lat(current_lat), parser.get_lon(current_lon));
+            let result = tags.and_then(|tags| self.delegate.visit_node(current_id, latitude, longitude, tags, info));
+            match result {
+                Ok(Flow::Stop) => return Ok(Flow::Stop),
+                Ok(Flow::Continue) => (),
+                Err(e) => self.handle_element_error(e.in_element(OsmEntityType::Node, current_id))?,
+            }
         }
 
-        Ok(())
+        Ok(Flow::Continue)
     }
 }
 
 impl<'a> BlobVisitor for OsmBlobVisitor<'a> {
-    fn visit_blob(&mut self, blob: &Blob) -> Result<(), PbfParseError> {
+    fn visit_blob(&mut self, blob: &Blob) -> VisitResult {
         let data = blob.data.as_ref();
         match blob.data_type {
             BlobType::DATA => {
                 let block: PrimitiveBlock = ::read_message_bytes(data)?;
-                let block_parser = OsmBlockParser::new(&block);
-                self.delegate.visit_block(block.get_lat_offset(), block.get_lon_offset(), block.get_granularity(), block.get_date_granularity())?;
-                self.delegate.visit_string_table(&block_parser.strings)?;
+                let block_parser = OsmBlockParser::new(&block, self.string_policy)?;
+                if self.delegate.visit_block(block.get_lat_offset(), block.get_lon_offset(), block.get_granularity(), block.get_date_granularity())? == Flow::Stop {
+                    return Ok(Flow::Stop);
+                }
+                if self.delegate.visit_string_table(&block_parser.strings)? == Flow::Stop {
+                    return Ok(Flow::Stop);
+                }
                 for group in block.get_primitivegroup() {
-                    self.visit_group(&block_parser, group)?;
+                    if self.visit_group(&block_parser, group)? == Flow::Stop {
+                        return Ok(Flow::Stop);
+                    }
                 }
                 self.delegate.end_block()?;
+                Ok(Flow::Continue)
             }
             BlobType::HEADER => {
                 let block: HeaderBlock = ::read_message_bytes(data)?;
-                self.delegate.visit_header(&block)?;
+                self.delegate.visit_header(&block)
             }
         }
-        Ok(())
     }
 
     fn end(&mut self) -> Result<(), PbfParseError> {
         self.delegate.end()
     }
 
-    fn handle_error(&mut self, error: &PbfParseError) -> bool {
-        self.delegate.handle_error(error)
+    fn handle_error(&mut self, error: &PbfParseError) -> ErrorPolicy {
+        match self.pending_policy.take() {
+            Some(policy) => policy,
+            None => self.delegate.handle_error(error),
+        }
     }
 }
 
@@ -205,18 +413,18 @@ struct OsmBlockParser<'a> {
     origin_longitude: i64,
     granularity: i64,
     date_granularity: i64,
-    strings: Vec<&'a str>,
+    strings: BlockStrings<'a>,
 }
 
 impl<'a> OsmBlockParser<'a> {
-    fn new(block: &'a PrimitiveBlock) -> OsmBlockParser<'a> {
-        OsmBlockParser {
+    fn new(block: &'a PrimitiveBlock, string_policy: InvalidStringPolicy) -> Result<OsmBlockParser<'a>, PbfParseError> {
+        Ok(OsmBlockParser {
             origin_latitude: block.get_lat_offset(),
             origin_longitude: block.get_lon_offset(),
             granularity: block.get_granularity() as i64,
             date_granularity: block.get_date_granularity() as i64,
-            strings: parse_string_table(block.get_stringtable()),
-        }
+            strings: BlockStrings::parse(block.get_stringtable(), string_policy)?,
+        })
     }
 
     fn get_lat(&self, lat: i64) -> f64 {
@@ -242,26 +450,163 @@ impl<'a> OsmBlockParser<'a> {
         }
     }
 
-    fn parse_tags(&self, keys: &'a [u32], values: &'a [u32]) -> Vec<(String, String)> {
+    fn parse_tags(&self, keys: &[u32], values: &[u32]) -> Result<Vec<(String, String)>, PbfParseError> {
+        if keys.len() != values.len() {
+            return Err(PbfParseError::MalformedData(format!(
+                "entity has {} tag keys but {} tag values", keys.len(), values.len()
+            )));
+        }
         keys.iter().zip(values)
-            .map(|(key, value)| (
-                self.strings[*key as usize].to_string(),
-                self.strings[*value as usize].to_string()
-            ))
+            .map(|(key, value)| Ok((self.get_string(*key as i64)?, self.get_string(*value as i64)?)))
             .collect()
     }
 
-    fn get_string(&self, id: i32) -> String {
-        self.strings[id as usize].to_string()
+    /// Parses the tags of a single dense node, always advancing `index` past the node's tags so that
+    /// following nodes can still be read if this one is malformed.
+    fn parse_dense_tags(&self, raw_tags: &[i32], index: &mut usize) -> Result<Vec<(String, String)>, PbfParseError> {
+        let mut tags: Vec<(String, String)> = Vec::new();
+        let mut error = None;
+        while *index < raw_tags.len() {
+            if raw_tags[*index] == 0 {
+                *index += 1;
+                break;
+            }
+            // If we haven't reached the end of this node's tags we should always have a key and value available
+            if *index + 2 > raw_tags.len() {
+                *index = raw_tags.len();
+                return Err(PbfParseError::MalformedData("tag key without a value".to_string()));
+            }
+            let key = self.get_string(raw_tags[*index] as i64);
+            let value = self.get_string(raw_tags[*index + 1] as i64);
+            *index += 2;
+            match (key, value) {
+                (Ok(key), Ok(value)) => tags.push((key, value)),
+                (Err(e), _) | (_, Err(e)) => if error.is_none() {
+                    error = Some(e);
+                },
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(tags),
+        }
+    }
+
+    fn get_string(&self, id: i64) -> Result<String, PbfParseError> {
+        self.lookup_string(id).map(|s| s.to_string())
+    }
+
+    fn lookup_string(&self, id: i64) -> Result<&str, PbfParseError> {
+        let string = if id >= 0 { self.strings.get(id as usize) } else { None };
+        string.ok_or_else(|| PbfParseError::MalformedData(format!(
+            "string index {} out of range for table of {} strings", id, self.strings.len()
+        )))
     }
 }
 
-fn parse_string_table<'a>(table: &'a StringTable) -> Vec<&'a str> {
-    let table_data = table.get_s();
-    table_data.iter()
-        .map(|s| str::from_utf8(s.as_ref()))
-        .filter_map(|s| s.ok())
-        .collect()
+/// The string table of a single block, decoded according to an `InvalidStringPolicy`.
+pub struct BlockStrings<'a> {
+    raw: Vec<&'a [u8]>,
+    strings: Vec<Cow<'a, str>>,
+    invalid: Vec<usize>,
+}
+
+impl<'a> BlockStrings<'a> {
+    fn parse(table: &'a StringTable, policy: InvalidStringPolicy) -> Result<BlockStrings<'a>, PbfParseError> {
+        let raw: Vec<&'a [u8]> = table.get_s().iter().map(|s| s.as_ref()).collect();
+        let mut strings = Vec::with_capacity(raw.len());
+        let mut invalid = Vec::new();
+        for (i, bytes) in raw.iter().enumerate() {
+            let string = match str::from_utf8(bytes) {
+                Ok(s) => Cow::Borrowed(s),
+                Err(e) => {
+                    invalid.push(i);
+                    match policy {
+                        InvalidStringPolicy::Lossy => String::from_utf8_lossy(bytes),
+                        InvalidStringPolicy::Raw => Cow::Borrowed(""),
+                        InvalidStringPolicy::Error => {
+                            return Err(PbfParseError::MalformedData(format!(
+                                "string table entry {} is not valid UTF-8: {}", i, e
+                            )));
+                        }
+                    }
+                }
+            };
+            strings.push(string);
+        }
+        Ok(BlockStrings { raw, strings, invalid })
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.strings.get(index).map(|s| s.as_ref())
+    }
+
+    pub fn get_raw(&self, index: usize) -> Option<&'a [u8]> {
+        self.raw.get(index).map(|s| *s)
+    }
+
+    /// Whether the entry at the given index was valid UTF-8, and so was decoded as stored whatever the policy.
+    pub fn is_valid(&self, index: usize) -> bool {
+        index < self.raw.len() && self.invalid.binary_search(&index).is_err()
+    }
+
+    /// The indices of the entries that were not valid UTF-8, in ascending order.
+    pub fn invalid_indices(&self) -> &[usize] {
+        &self.invalid
+    }
+}
+
+/// An owned entity, for visitors that need to inspect or transform entities as a whole.
+#[derive(Debug, Clone)]
+pub enum Entity {
+    Node { id: i64, latitude: f64, longitude: f64, tags: Vec<(String, String)>, info: EntityInfo },
+    Way { id: i64, nodes: Vec<NodeReference>, tags: Vec<(String, String)>, info: EntityInfo },
+    Relation { id: i64, members: Vec<MemberReference>, tags: Vec<(String, String)>, info: EntityInfo },
+}
+
+impl Entity {
+    pub fn id(&self) -> i64 {
+        match *self {
+            Entity::Node { id, .. } | Entity::Way { id, .. } | Entity::Relation { id, .. } => id,
+        }
+    }
+
+    pub fn entity_type(&self) -> OsmEntityType {
+        match *self {
+            Entity::Node { .. } => OsmEntityType::Node,
+            Entity::Way { .. } => OsmEntityType::Way,
+            Entity::Relation { .. } => OsmEntityType::Relation,
+        }
+    }
+
+    pub fn tags(&self) -> &[(String, String)] {
+        match *self {
+            Entity::Node { ref tags, .. } | Entity::Way { ref tags, .. } | Entity::Relation { ref tags, .. } => tags,
+        }
+    }
+
+    pub fn info(&self) -> EntityInfo {
+        match *self {
+            Entity::Node { info, .. } | Entity::Way { info, .. } | Entity::Relation { info, .. } => info,
+        }
+    }
+
+    /// Passes this entity to the matching visit method of the given visitor.
+    pub fn visit(self, visitor: &mut OsmVisitor) -> VisitResult {
+        match self {
+            Entity::Node { id, latitude, longitude, tags, info } => visitor.visit_node(id, latitude, longitude, tags, info),
+            Entity::Way { id, nodes, tags, info } => visitor.visit_way(id, nodes, tags, info),
+            Entity::Relation { id, members, tags, info } => visitor.visit_relation(id, members, tags, info),
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -269,11 +614,11 @@ pub struct NodeReference {
     pub id: i64,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct MemberReference {
     pub id: i64,
     pub entity_type: OsmEntityType,
-    pub role_sid: i32,
+    pub role: String,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -286,13 +631,24 @@ pub struct EntityInfo {
     pub visible: bool,
 }
 
-#[derive(Debug, Copy, Clone)]
+/// The type of an entity, ordered as entities of each type appear in a sorted file.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Copy, Clone)]
 pub enum OsmEntityType {
     Node,
     Way,
     Relation,
 }
 
+impl fmt::Display for OsmEntityType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OsmEntityType::Node => write!(f, "node"),
+            OsmEntityType::Way => write!(f, "way"),
+            OsmEntityType::Relation => write!(f, "relation"),
+        }
+    }
+}
+
 impl From<Relation_MemberType> for OsmEntityType {
     fn from(mem_type: Relation_MemberType) -> Self {
         use protos::osm::Relation_MemberType::*;
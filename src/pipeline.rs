@@ -0,0 +1,187 @@
+use ::PbfParseError;
+use combinator::FilterVisitor;
+use idset::IdSet;
+use osm::{Entity, EntityInfo, MemberReference, NodeReference, OsmEntityType, OsmReader};
+use reader::ErrorSummary;
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+use visitor::{Flow, OsmVisitor, VisitResult};
+
+/// The IDs of every entity selected by a `ReferencePipeline`.
+#[derive(Debug, Default)]
+pub struct Selection {
+    pub nodes: IdSet,
+    pub ways: IdSet,
+    pub relations: IdSet,
+}
+
+impl Selection {
+    pub fn contains(&self, entity_type: OsmEntityType, id: i64) -> bool {
+        match entity_type {
+            OsmEntityType::Node => self.nodes.contains(id),
+            OsmEntityType::Way => self.ways.contains(id),
+            OsmEntityType::Relation => self.relations.contains(id),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len() + self.ways.len() + self.relations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Selects seed entities with a predicate and runs as many passes as needed to also select everything they
+/// reference: relation members (recursively), the nodes of selected ways, and the nodes of ways selected
+/// through relations.
+pub struct ReferencePipeline<F: FnMut(&Entity) -> bool> {
+    seed: F,
+}
+
+impl<F: FnMut(&Entity) -> bool> ReferencePipeline<F> {
+    pub fn new(seed: F) -> ReferencePipeline<F> {
+        ReferencePipeline { seed }
+    }
+
+    /// Collects the referentially complete selection, returning it along with the errors skipped over all passes.
+    pub fn collect<T: Read + Seek>(&mut self, reader: &mut OsmReader<T>) -> Result<(Selection, ErrorSummary), PbfParseError> {
+        let mut selection = Selection::default();
+
+        let mut seed_visitor = SeedVisitor {
+            seed: &mut self.seed,
+            selection: &mut selection,
+            child_relations: HashMap::new(),
+        };
+        let mut summary = reader.accept(&mut seed_visitor)?;
+        let child_relations = seed_visitor.child_relations;
+
+        // Relations can reference relations that appear later in the file, so resolve these in memory
+        let mut pending: Vec<i64> = selection.relations.iter().collect();
+        while let Some(id) = pending.pop() {
+            if let Some(children) = child_relations.get(&id) {
+                for child in children {
+                    if selection.relations.insert(*child) {
+                        pending.push(*child);
+                    }
+                }
+            }
+        }
+
+        if selection.relations.is_empty() {
+            return Ok((selection, summary));
+        }
+
+        let mut member_visitor = RelationMemberVisitor { selection: &mut selection, added_ways: IdSet::new() };
+        summary.merge(reader.accept(&mut member_visitor)?);
+        let added_ways = member_visitor.added_ways;
+
+        if !added_ways.is_empty() {
+            let mut way_visitor = WayNodeVisitor::new(&added_ways, &mut selection.nodes);
+            summary.merge(reader.accept(&mut way_visitor)?);
+        }
+
+        Ok((selection, summary))
+    }
+
+    /// Collects the referentially complete selection and writes every selected entity to the output visitor
+    /// in a final pass.
+    pub fn run<T: Read + Seek>(&mut self, reader: &mut OsmReader<T>, output: &mut OsmVisitor) -> Result<(Selection, ErrorSummary), PbfParseError> {
+        let (selection, mut summary) = self.collect(reader)?;
+        {
+            let mut filter = FilterVisitor::new(output, |entity| selection.contains(entity.entity_type(), entity.id()));
+            summary.merge(reader.accept(&mut filter)?);
+        }
+        Ok((selection, summary))
+    }
+}
+
+struct SeedVisitor<'a, F: 'a + FnMut(&Entity) -> bool> {
+    seed: &'a mut F,
+    selection: &'a mut Selection,
+    /// The relation members of every relation in the file that has any
+    child_relations: HashMap<i64, Vec<i64>>,
+}
+
+impl<'a, F: 'a + FnMut(&Entity) -> bool> OsmVisitor for SeedVisitor<'a, F> {
+    fn visit_node(&mut self, id: i64, latitude: f64, longitude: f64, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+        if (self.seed)(&Entity::Node { id, latitude, longitude, tags, info }) {
+            self.selection.nodes.insert(id);
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn visit_way(&mut self, id: i64, nodes: Vec<NodeReference>, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+        let entity = Entity::Way { id, nodes, tags, info };
+        if (self.seed)(&entity) {
+            self.selection.ways.insert(id);
+            if let Entity::Way { nodes, .. } = entity {
+                self.selection.nodes.extend(nodes.iter().map(|node| node.id));
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn visit_relation(&mut self, id: i64, members: Vec<MemberReference>, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+        let children: Vec<i64> = members.iter()
+            .filter(|member| member.entity_type == OsmEntityType::Relation)
+            .map(|member| member.id)
+            .collect();
+        if !children.is_empty() {
+            self.child_relations.insert(id, children);
+        }
+        if (self.seed)(&Entity::Relation { id, members, tags, info }) {
+            self.selection.relations.insert(id);
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+struct RelationMemberVisitor<'a> {
+    selection: &'a mut Selection,
+    /// Ways selected through relation membership whose nodes still need to be collected
+    added_ways: IdSet,
+}
+
+impl<'a> OsmVisitor for RelationMemberVisitor<'a> {
+    fn visit_relation(&mut self, id: i64, members: Vec<MemberReference>, _tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        if self.selection.relations.contains(id) {
+            for member in members {
+                match member.entity_type {
+                    OsmEntityType::Node => {
+                        self.selection.nodes.insert(member.id);
+                    }
+                    OsmEntityType::Way => {
+                        if self.selection.ways.insert(member.id) {
+                            self.added_ways.insert(member.id);
+                        }
+                    }
+                    OsmEntityType::Relation => (),
+                }
+            }
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// Adds the nodes of the given ways to a node set.
+pub struct WayNodeVisitor<'a> {
+    ways: &'a IdSet,
+    nodes: &'a mut IdSet,
+}
+
+impl<'a> WayNodeVisitor<'a> {
+    pub fn new(ways: &'a IdSet, nodes: &'a mut IdSet) -> WayNodeVisitor<'a> {
+        WayNodeVisitor { ways, nodes }
+    }
+}
+
+impl<'a> OsmVisitor for WayNodeVisitor<'a> {
+    fn visit_way(&mut self, id: i64, nodes: Vec<NodeReference>, _tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        if self.ways.contains(id) {
+            self.nodes.extend(nodes.iter().map(|node| node.id));
+        }
+        Ok(Flow::Continue)
+    }
+}
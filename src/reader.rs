@@ -1,54 +1,165 @@
 use ::PbfParseError;
-use blob::Blob;
+use blob::{Blob, BlobType};
 use std::io::{Read, Seek, SeekFrom};
-use visitor::BlobVisitor;
+use visitor::{BlobVisitor, ErrorPolicy, Flow};
+
+const RESYNC_CHUNK_SIZE: usize = 64 * 1024;
+const MAX_SUMMARY_ERRORS: usize = 100;
 
 pub struct BlobReader<'a, T: 'a + Read + Seek> {
     reader: &'a mut T,
+    resynchronize: bool,
 }
 
 impl<'a, T: 'a + Read + Seek> BlobReader<'a, T> {
     pub fn from(reader: &'a mut T) -> BlobReader<'a, T> {
-        BlobReader { reader }
+        BlobReader { reader, resynchronize: false }
     }
 
-    pub fn accept(&mut self, visitor: &mut BlobVisitor) {
-        match self.try_accept(visitor) {
-            Err(ref e) => {
-                visitor.handle_error(e);
-            }
-            _ => (),
-        }
+    /// When enabled, a blob that cannot be parsed is skipped by scanning forward for the next plausible
+    /// blob header instead of ending the traversal.
+    pub fn set_resynchronize(&mut self, resynchronize: bool) {
+        self.resynchronize = resynchronize;
     }
 
-    fn try_accept(&mut self, visitor: &mut BlobVisitor) -> Result<(), PbfParseError> {
+    /// Visits every blob in the file, returning the errors that were skipped according to the visitor's
+    /// `ErrorPolicy`, or the error that caused the traversal to be aborted.
+    pub fn accept(&mut self, visitor: &mut BlobVisitor) -> Result<ErrorSummary, PbfParseError> {
+        let mut summary = ErrorSummary::default();
+        let length = self.reader.seek(SeekFrom::End(0))?;
         self.reader.seek(SeekFrom::Start(0))?;
+        let mut index = 0;
         loop {
-            let result = parse_blob(self.reader, visitor);
-            match result {
-                Err(PbfParseError::Eof) => break,
-                Err(ref e) => {
-                    if visitor.handle_error(e) {
-                        break;
+            let offset = self.reader.seek(SeekFrom::Current(0))?;
+            if offset >= length {
+                break;
+            }
+            match Blob::parse(self.reader) {
+                Ok(blob) => match visitor.visit_blob(&blob) {
+                    Ok(Flow::Stop) => break,
+                    Ok(Flow::Continue) => (),
+                    Err(e) => {
+                        let error = e.in_blob(index, offset);
+                        if visitor.handle_error(&error) == ErrorPolicy::Abort {
+                            return Err(error);
+                        }
+                        summary.skip_blob(error);
+                    }
+                },
+                Err(e) => {
+                    let error = e.in_blob(index, offset);
+                    if visitor.handle_error(&error) == ErrorPolicy::Abort {
+                        return Err(error);
+                    }
+                    summary.skip_blob(error);
+
+                    // The stream position is undefined after a failed parse, so we can only continue by resynchronizing
+                    let next = if self.resynchronize { self.find_next_header(offset + 1, length)? } else { None };
+                    match next {
+                        Some(next) => {
+                            summary.skipped_ranges.push((offset, next));
+                            self.reader.seek(SeekFrom::Start(next))?;
+                        }
+                        None => {
+                            summary.skipped_ranges.push((offset, length));
+                            break;
+                        }
                     }
                 }
-                _ => (),
             }
+            index += 1;
         }
         visitor.end()?;
-        Ok(())
+        Ok(summary)
     }
-}
 
-fn parse_blob(reader: &mut Read, visitor: &mut BlobVisitor) -> Result<Blob, PbfParseError> {
-    match Blob::parse(reader) {
-        Ok(blob) => {
-            let result = visitor.visit_blob(&blob);
-            match result {
-                Ok(_) => Ok(blob),
-                Err(e) => Err(e),
+    fn find_next_header(&mut self, from: u64, length: u64) -> Result<Option<u64>, PbfParseError> {
+        // A serialized BlobHeader starts with its type string, preceded by the 4 byte length prefix
+        let markers: Vec<Vec<u8>> = [BlobType::HEADER, BlobType::DATA].iter()
+            .map(|ty| {
+                let name: String = (*ty).into();
+                let mut marker = vec![0x0A, name.len() as u8];
+                marker.extend(name.into_bytes());
+                marker
+            })
+            .collect();
+        let overlap = 4 + markers.iter().map(|m| m.len()).max().unwrap_or(0);
+
+        let mut buffer = vec![0u8; RESYNC_CHUNK_SIZE];
+        let mut position = from;
+        while position < length {
+            self.reader.seek(SeekFrom::Start(position))?;
+            let chunk_length = read_chunk(self.reader, &mut buffer)?;
+            let chunk = &buffer[..chunk_length];
+
+            for i in 4..chunk_length {
+                if markers.iter().any(|m| chunk[i..].starts_with(m)) {
+                    let candidate = position + i as u64 - 4;
+                    self.reader.seek(SeekFrom::Start(candidate))?;
+                    if Blob::probe(self.reader, length - candidate) {
+                        return Ok(Some(candidate));
+                    }
+                }
+            }
+
+            if chunk_length < buffer.len() {
+                break;
             }
+            position += (chunk_length - overlap) as u64;
+        }
+        Ok(None)
+    }
+}
+
+fn read_chunk(reader: &mut Read, buffer: &mut [u8]) -> Result<usize, PbfParseError> {
+    let mut total = 0;
+    while total < buffer.len() {
+        match reader.read(&mut buffer[total..])? {
+            0 => break,
+            read => total += read,
+        }
+    }
+    Ok(total)
+}
+
+/// The errors that were skipped over during a traversal according to the visitor's `ErrorPolicy`.
+#[derive(Debug, Default)]
+pub struct ErrorSummary {
+    pub skipped_blobs: usize,
+    pub skipped_elements: usize,
+    /// Byte ranges that could not be read, either resynchronized over or left unread at the end of the file
+    pub skipped_ranges: Vec<(u64, u64)>,
+    /// The first skipped errors, up to a fixed limit
+    pub errors: Vec<PbfParseError>,
+}
+
+impl ErrorSummary {
+    pub fn is_clean(&self) -> bool {
+        self.skipped_blobs == 0 && self.skipped_elements == 0
+    }
+
+    pub fn skip_blob(&mut self, error: PbfParseError) {
+        self.skipped_blobs += 1;
+        self.push_error(error);
+    }
+
+    pub fn skip_element(&mut self, error: PbfParseError) {
+        self.skipped_elements += 1;
+        self.push_error(error);
+    }
+
+    pub fn merge(&mut self, other: ErrorSummary) {
+        self.skipped_blobs += other.skipped_blobs;
+        self.skipped_elements += other.skipped_elements;
+        self.skipped_ranges.extend(other.skipped_ranges);
+        for error in other.errors {
+            self.push_error(error);
+        }
+    }
+
+    fn push_error(&mut self, error: PbfParseError) {
+        if self.errors.len() < MAX_SUMMARY_ERRORS {
+            self.errors.push(error);
         }
-        err => err,
     }
 }
@@ -0,0 +1,135 @@
+use ::PbfParseError;
+use combinator::MapVisitor;
+use idset::{IdIndex, IdSet};
+use osm::{Entity, EntityInfo, MemberReference, NodeReference, OsmEntityType, OsmReader};
+use reader::ErrorSummary;
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+use visitor::{Flow, OsmVisitor, VisitResult};
+
+/// How many entities of each type a renumbering found, and how many IDs it had to invent for references to
+/// entities missing from the file.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct RenumberSummary {
+    pub nodes: usize,
+    pub ways: usize,
+    pub relations: usize,
+    pub missing_nodes: usize,
+    pub missing_ways: usize,
+    pub missing_relations: usize,
+}
+
+/// Renumbers the nodes, ways and relations of a file to dense ranges of IDs, by default each starting at 1.
+///
+/// A first pass collects the IDs of each type, and a second writes every entity with its ID replaced by its
+/// position among the IDs of its type, rewriting way node references and relation members to match. New IDs
+/// keep the order of the old ones, so a sorted file stays sorted. References to entities missing from the file
+/// are given IDs following the range of their type, in the order they are first seen.
+pub struct Renumber {
+    starts: [i64; 3],
+}
+
+impl Renumber {
+    pub fn new() -> Renumber {
+        Renumber { starts: [1; 3] }
+    }
+
+    /// Sets the first ID given to entities of a type.
+    pub fn set_start(&mut self, entity_type: OsmEntityType, start: i64) {
+        self.starts[type_index(entity_type)] = start;
+    }
+
+    pub fn run<T: Read + Seek>(&self, reader: &mut OsmReader<T>, output: &mut OsmVisitor) -> Result<(RenumberSummary, ErrorSummary), PbfParseError> {
+        let mut collector = IdCollector { ids: [IdSet::new(), IdSet::new(), IdSet::new()] };
+        let mut summary = reader.accept(&mut collector)?;
+
+        let [nodes, ways, relations] = collector.ids;
+        let mut mapping = IdMapping {
+            starts: self.starts,
+            indexes: [IdIndex::new(nodes), IdIndex::new(ways), IdIndex::new(relations)],
+            missing: [HashMap::new(), HashMap::new(), HashMap::new()],
+        };
+        {
+            let mut renumberer = MapVisitor::new(output, |entity| Some(mapping.renumber(entity)));
+            summary.merge(reader.accept(&mut renumberer)?);
+        }
+
+        let renumbered = RenumberSummary {
+            nodes: mapping.indexes[0].len(),
+            ways: mapping.indexes[1].len(),
+            relations: mapping.indexes[2].len(),
+            missing_nodes: mapping.missing[0].len(),
+            missing_ways: mapping.missing[1].len(),
+            missing_relations: mapping.missing[2].len(),
+        };
+        Ok((renumbered, summary))
+    }
+}
+
+fn type_index(entity_type: OsmEntityType) -> usize {
+    match entity_type {
+        OsmEntityType::Node => 0,
+        OsmEntityType::Way => 1,
+        OsmEntityType::Relation => 2,
+    }
+}
+
+struct IdCollector {
+    ids: [IdSet; 3],
+}
+
+impl OsmVisitor for IdCollector {
+    fn visit_node(&mut self, id: i64, _latitude: f64, _longitude: f64, _tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        self.ids[0].insert(id);
+        Ok(Flow::Continue)
+    }
+
+    fn visit_way(&mut self, id: i64, _nodes: Vec<NodeReference>, _tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        self.ids[1].insert(id);
+        Ok(Flow::Continue)
+    }
+
+    fn visit_relation(&mut self, id: i64, _members: Vec<MemberReference>, _tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        self.ids[2].insert(id);
+        Ok(Flow::Continue)
+    }
+}
+
+struct IdMapping {
+    starts: [i64; 3],
+    indexes: [IdIndex; 3],
+    /// The IDs given to referenced entities that are missing from the file
+    missing: [HashMap<i64, i64>; 3],
+}
+
+impl IdMapping {
+    fn map(&mut self, entity_type: OsmEntityType, id: i64) -> i64 {
+        let index = type_index(entity_type);
+        let start = self.starts[index];
+        match self.indexes[index].position(id) {
+            Some(position) => start + position as i64,
+            None => {
+                let next = start + (self.indexes[index].len() + self.missing[index].len()) as i64;
+                *self.missing[index].entry(id).or_insert(next)
+            }
+        }
+    }
+
+    fn renumber(&mut self, entity: Entity) -> Entity {
+        match entity {
+            Entity::Node { id, latitude, longitude, tags, info } => {
+                Entity::Node { id: self.map(OsmEntityType::Node, id), latitude, longitude, tags, info }
+            }
+            Entity::Way { id, nodes, tags, info } => {
+                let nodes = nodes.into_iter().map(|node| NodeReference { id: self.map(OsmEntityType::Node, node.id) }).collect();
+                Entity::Way { id: self.map(OsmEntityType::Way, id), nodes, tags, info }
+            }
+            Entity::Relation { id, members, tags, info } => {
+                let members = members.into_iter()
+                    .map(|member| MemberReference { id: self.map(member.entity_type, member.id), ..member })
+                    .collect();
+                Entity::Relation { id: self.map(OsmEntityType::Relation, id), members, tags, info }
+            }
+        }
+    }
+}
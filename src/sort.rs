@@ -0,0 +1,202 @@
+use ::{PbfErrorKind, PbfParseError};
+use merge::{merge, MergeSummary};
+use osm::{Entity, EntityInfo, MemberReference, NodeReference, OsmReader};
+use protos::osm::HeaderBlock;
+use reader::ErrorSummary;
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use visitor::{ErrorPolicy, Flow, OsmVisitor, VisitResult};
+use writer::OsmWriterVisitor;
+
+const DEFAULT_RUN_SIZE: usize = 1_000_000;
+/// The most runs merged at once, to stay well within limits on open files
+const MAX_MERGE_WIDTH: usize = 64;
+
+/// Numbers temporary files uniquely within the process
+static NEXT_TEMP_FILE: AtomicUsize = AtomicUsize::new(0);
+
+/// Sorts a file by type and then ID with an external merge sort.
+///
+/// Entities are collected into runs of a fixed size, each of which is sorted in memory and spilled to a temporary
+/// PBF file. The runs are then merged into the output, first into intermediate runs if there are too many to
+/// open at once, so memory use is bounded by the run size rather than the size of the file. As with `merge`, an
+/// entity stored more than once is only written in its newest version, and the output header is the input's,
+/// declaring `Sort.Type_then_ID`.
+pub struct ExternalSort {
+    run_size: usize,
+    temp_dir: PathBuf,
+}
+
+impl ExternalSort {
+    pub fn new() -> ExternalSort {
+        ExternalSort { run_size: DEFAULT_RUN_SIZE, temp_dir: env::temp_dir() }
+    }
+
+    /// Sets the number of entities sorted in memory at once.
+    pub fn set_run_size(&mut self, run_size: usize) {
+        self.run_size = run_size.max(1);
+    }
+
+    /// Sets the directory runs are spilled to, which defaults to the system's temporary directory.
+    pub fn set_temp_dir<P: AsRef<Path>>(&mut self, temp_dir: P) {
+        self.temp_dir = temp_dir.as_ref().to_path_buf();
+    }
+
+    pub fn run<T: Read + Seek>(&self, reader: &mut OsmReader<T>, output: &mut OsmVisitor) -> Result<(MergeSummary, ErrorSummary), PbfParseError> {
+        let mut runs = RunWriter {
+            run_size: self.run_size,
+            temp_dir: &self.temp_dir,
+            header: None,
+            entities: Vec::new(),
+            files: TempFiles { paths: Vec::new() },
+            dropped: MergeSummary::default(),
+        };
+        let summary = reader.accept(&mut runs)?;
+
+        let mut dropped = runs.dropped;
+        let mut files = runs.files;
+        while files.paths.len() > MAX_MERGE_WIDTH {
+            let batch: Vec<PathBuf> = files.paths.drain(..MAX_MERGE_WIDTH).collect();
+            let batch = TempFiles { paths: batch };
+            let path = files.create_path(&self.temp_dir);
+            let mut file = BufWriter::new(File::create(&path)?);
+            let merged = merge_files(&batch.paths, &mut OsmWriterVisitor::new(&mut file, true))?;
+            file.flush()?;
+            dropped.duplicates += merged.duplicates;
+            dropped.conflicts += merged.conflicts;
+        }
+
+        let mut merged = merge_files(&files.paths, output)?;
+        merged.duplicates += dropped.duplicates;
+        merged.conflicts += dropped.conflicts;
+        Ok((merged, summary))
+    }
+}
+
+fn merge_files(paths: &[PathBuf], output: &mut OsmVisitor) -> Result<MergeSummary, PbfParseError> {
+    let mut files = Vec::with_capacity(paths.len());
+    for path in paths {
+        files.push(File::open(path)?);
+    }
+    merge(files.iter_mut().collect(), output)
+}
+
+/// Temporary files, deleted when dropped so that they are cleaned up even if sorting fails.
+struct TempFiles {
+    paths: Vec<PathBuf>,
+}
+
+impl TempFiles {
+    fn create_path(&mut self, temp_dir: &Path) -> PathBuf {
+        let number = NEXT_TEMP_FILE.fetch_add(1, Ordering::SeqCst);
+        let path = temp_dir.join(format!("osm-sort-{}-{}.osm.pbf", process::id(), number));
+        self.paths.push(path.clone());
+        path
+    }
+}
+
+impl Drop for TempFiles {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+struct RunWriter<'a> {
+    run_size: usize,
+    temp_dir: &'a Path,
+    header: Option<HeaderBlock>,
+    entities: Vec<Entity>,
+    files: TempFiles,
+    /// Counts the copies of entities left out of runs, since merging the runs won't see them
+    dropped: MergeSummary,
+}
+
+impl<'a> RunWriter<'a> {
+    fn add(&mut self, entity: Entity) -> VisitResult {
+        self.entities.push(entity);
+        if self.entities.len() >= self.run_size {
+            self.spill()?;
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn spill(&mut self) -> Result<(), PbfParseError> {
+        let mut entities = mem::replace(&mut self.entities, Vec::new());
+        // Among copies of the same entity the newest sorts last, and is the one kept
+        entities.sort_by(|a, b| {
+            let (a_info, b_info) = (a.info(), b.info());
+            (a.entity_type(), a.id(), a_info.version, a_info.timestamp).cmp(&(b.entity_type(), b.id(), b_info.version, b_info.timestamp))
+        });
+
+        let path = self.files.create_path(self.temp_dir);
+        let mut file = BufWriter::new(File::create(&path)?);
+        {
+            let mut writer = OsmWriterVisitor::new(&mut file, true);
+            if let Some(ref header) = self.header {
+                writer.visit_header(header)?;
+            }
+            let mut entities = entities.into_iter().peekable();
+            while let Some(entity) = entities.next() {
+                let newer_version = entities.peek()
+                    .filter(|next| (next.entity_type(), next.id()) == (entity.entity_type(), entity.id()))
+                    .map(|next| next.info().version);
+                match newer_version {
+                    Some(version) if version == entity.info().version => self.dropped.duplicates += 1,
+                    Some(_) => self.dropped.conflicts += 1,
+                    None => {
+                        entity.visit(&mut writer)?;
+                    }
+                }
+            }
+            writer.end()?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+}
+
+impl<'a> OsmVisitor for RunWriter<'a> {
+    fn visit_header(&mut self, block: &HeaderBlock) -> VisitResult {
+        if self.header.is_none() {
+            self.header = Some(block.clone());
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn visit_node(&mut self, id: i64, latitude: f64, longitude: f64, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+        self.add(Entity::Node { id, latitude, longitude, tags, info })
+    }
+
+    fn visit_way(&mut self, id: i64, nodes: Vec<NodeReference>, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+        self.add(Entity::Way { id, nodes, tags, info })
+    }
+
+    fn visit_relation(&mut self, id: i64, members: Vec<MemberReference>, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
+        self.add(Entity::Relation { id, members, tags, info })
+    }
+
+    /// Spills the last run, or an empty one if the file has no entities so that the header still reaches the
+    /// output.
+    fn end(&mut self) -> Result<(), PbfParseError> {
+        if !self.entities.is_empty() || self.files.paths.is_empty() {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Failing to spill a run leaves the sort incomplete, so i/o errors abort rather than skip a blob.
+    fn handle_error(&mut self, error: &PbfParseError) -> ErrorPolicy {
+        if error.kind() == PbfErrorKind::Io {
+            ErrorPolicy::Abort
+        } else {
+            ErrorPolicy::SkipBlob
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use ::PbfParseError;
+use osm::{EntityInfo, MemberReference, NodeReference, OsmEntityType};
+use serde_json;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use visitor::{Flow, OsmVisitor, VisitResult};
+
+const DEFAULT_MAX_VALUES: usize = 10_000;
+/// The number of index bits of the distinct value estimator, giving 1024 registers and a standard error of about 3%
+const ESTIMATOR_BITS: u32 = 10;
+
+/// How many entities of each type something was counted on.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct TypeCounts {
+    pub nodes: u64,
+    pub ways: u64,
+    pub relations: u64,
+}
+
+impl TypeCounts {
+    pub fn total(&self) -> u64 {
+        self.nodes + self.ways + self.relations
+    }
+
+    fn add(&mut self, entity_type: OsmEntityType) {
+        match entity_type {
+            OsmEntityType::Node => self.nodes += 1,
+            OsmEntityType::Way => self.ways += 1,
+            OsmEntityType::Relation => self.relations += 1,
+        }
+    }
+}
+
+/// The statistics of a single key and its values.
+///
+/// Only a bounded number of values are tracked per key. When a key has more, the least frequent values are
+/// dropped, so a dropped value that shows up again is counted from zero, possibly more than once. Once any have
+/// been dropped, which `distinct_exact` then reports as false, the counts of the remaining values may be too low
+/// by an unknown amount, and the number of distinct values is estimated.
+#[derive(Debug, Clone, Default)]
+pub struct KeyStatistics {
+    pub counts: TypeCounts,
+    values: HashMap<String, TypeCounts>,
+    distinct: Option<DistinctEstimator>,
+}
+
+impl KeyStatistics {
+    pub fn distinct_values(&self) -> u64 {
+        match self.distinct {
+            Some(ref estimator) => estimator.estimate().max(self.values.len() as u64),
+            None => self.values.len() as u64,
+        }
+    }
+
+    /// Whether `distinct_values` is an exact count rather than an estimate.
+    pub fn distinct_exact(&self) -> bool {
+        self.distinct.is_none()
+    }
+
+    /// The most frequent values with their counts, most frequent first.
+    pub fn top_values(&self, limit: usize) -> Vec<(&str, &TypeCounts)> {
+        let mut values: Vec<(&str, &TypeCounts)> = self.values.iter().map(|(value, counts)| (value.as_str(), counts)).collect();
+        values.sort_by(|a, b| by_count(a.1, b.1).then_with(|| a.0.cmp(b.0)));
+        values.truncate(limit);
+        values
+    }
+
+    fn add(&mut self, entity_type: OsmEntityType, value: &str, max_values: usize) {
+        self.counts.add(entity_type);
+        if let Some(ref mut estimator) = self.distinct {
+            estimator.insert(value);
+        }
+        if let Some(counts) = self.values.get_mut(value) {
+            counts.add(entity_type);
+            return;
+        }
+        let mut counts = TypeCounts::default();
+        counts.add(entity_type);
+        self.values.insert(value.to_string(), counts);
+
+        // Pruning to half the table at a time keeps the cost of sorting it low per added value
+        if self.values.len() > 2 * max_values {
+            self.prune(max_values);
+        }
+    }
+
+    fn prune(&mut self, max_values: usize) {
+        if self.distinct.is_none() {
+            let mut estimator = DistinctEstimator::new();
+            for value in self.values.keys() {
+                estimator.insert(value);
+            }
+            self.distinct = Some(estimator);
+        }
+
+        let mut values: Vec<(String, TypeCounts)> = self.values.drain().collect();
+        values.sort_by(|a, b| by_count(&a.1, &b.1));
+        values.truncate(max_values);
+        self.values.extend(values);
+    }
+}
+
+fn by_count(a: &TypeCounts, b: &TypeCounts) -> Ordering {
+    b.total().cmp(&a.total())
+}
+
+/// Estimates the number of distinct values added to it in fixed memory, with the HyperLogLog algorithm.
+#[derive(Debug, Clone)]
+struct DistinctEstimator {
+    registers: Vec<u8>,
+}
+
+impl DistinctEstimator {
+    fn new() -> DistinctEstimator {
+        DistinctEstimator { registers: vec![0; 1 << ESTIMATOR_BITS] }
+    }
+
+    fn insert(&mut self, value: &str) {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+        let index = (hash >> (64 - ESTIMATOR_BITS)) as usize;
+        let rank = ((hash << ESTIMATOR_BITS).leading_zeros() + 1).min(64 - ESTIMATOR_BITS + 1) as u8;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    fn estimate(&self) -> u64 {
+        let count = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / count);
+        let sum: f64 = self.registers.iter().map(|register| 2f64.powi(-(*register as i32))).sum();
+        let estimate = alpha * count * count / sum;
+
+        // Small cardinalities are estimated more accurately from the number of registers still empty
+        let empty = self.registers.iter().filter(|register| **register == 0).count();
+        if estimate <= 2.5 * count && empty > 0 {
+            (count * (count / empty as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+}
+
+/// Collects taginfo-style statistics in a single pass: how often each key is used, how often each of its values
+/// is used, and how many distinct values it has, counted separately for each type of entity.
+///
+/// Every key is kept, but the number of values tracked for each key is bounded, as described for
+/// `KeyStatistics`, so that keys with a value per entity like `name` or `addr:housenumber` don't use memory in
+/// proportion to the file.
+#[derive(Debug, Clone)]
+pub struct TagStatistics {
+    /// The number of entities of each type, tagged or not
+    pub entities: TypeCounts,
+    /// The number of entities of each type with at least one tag
+    pub tagged: TypeCounts,
+    keys: HashMap<String, KeyStatistics>,
+    max_values: usize,
+}
+
+impl TagStatistics {
+    pub fn new() -> TagStatistics {
+        TagStatistics {
+            entities: TypeCounts::default(),
+            tagged: TypeCounts::default(),
+            keys: HashMap::new(),
+            max_values: DEFAULT_MAX_VALUES,
+        }
+    }
+
+    /// Sets how many values are kept per key once it has more than twice as many.
+    pub fn set_max_values(&mut self, max_values: usize) {
+        self.max_values = max_values.max(1);
+    }
+
+    pub fn key(&self, key: &str) -> Option<&KeyStatistics> {
+        self.keys.get(key)
+    }
+
+    /// The most frequent keys with their statistics, most frequent first.
+    pub fn top_keys(&self, limit: usize) -> Vec<(&str, &KeyStatistics)> {
+        let mut keys: Vec<(&str, &KeyStatistics)> = self.keys.iter().map(|(key, stats)| (key.as_str(), stats)).collect();
+        keys.sort_by(|a, b| by_count(&a.1.counts, &b.1.counts).then_with(|| a.0.cmp(b.0)));
+        keys.truncate(limit);
+        keys
+    }
+
+    /// Writes one row per key followed by a row per value of that key, for up to `top_keys` keys and up to
+    /// `top_values` values each. Key rows have an empty value column, and value rows empty distinct columns.
+    pub fn write_csv(&self, output: &mut Write, top_keys: usize, top_values: usize) -> Result<(), PbfParseError> {
+        writeln!(output, "key,value,count,nodes,ways,relations,distinct_values,distinct_exact")?;
+        for (key, stats) in self.top_keys(top_keys) {
+            writeln!(output, "{},,{},{},{}", escape_csv(key), format_counts_csv(&stats.counts), stats.distinct_values(), stats.distinct_exact())?;
+            for (value, counts) in stats.top_values(top_values) {
+                writeln!(output, "{},{},{},,", escape_csv(key), escape_csv(value), format_counts_csv(counts))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_json(&self, output: &mut Write, top_keys: usize, top_values: usize) -> Result<(), PbfParseError> {
+        write!(output, "{{\"entities\":{},\"tagged\":{},\"keys\":[", format_counts_json(&self.entities), format_counts_json(&self.tagged))?;
+        for (index, (key, stats)) in self.top_keys(top_keys).into_iter().enumerate() {
+            if index > 0 {
+                output.write_all(b",")?;
+            }
+            let values: Vec<String> = stats.top_values(top_values).into_iter()
+                .map(|(value, counts)| format!("{{\"value\":{},\"counts\":{}}}", json_string(value), format_counts_json(counts)))
+                .collect();
+            write!(
+                output,
+                "\n{{\"key\":{},\"counts\":{},\"distinct_values\":{},\"distinct_exact\":{},\"values\":[{}]}}",
+                json_string(key), format_counts_json(&stats.counts), stats.distinct_values(), stats.distinct_exact(), values.join(",")
+            )?;
+        }
+        output.write_all(b"\n]}\n")?;
+        Ok(())
+    }
+
+    fn add(&mut self, entity_type: OsmEntityType, tags: &[(String, String)]) {
+        self.entities.add(entity_type);
+        if tags.is_empty() {
+            return;
+        }
+        self.tagged.add(entity_type);
+        let max_values = self.max_values;
+        for (key, value) in tags {
+            match self.keys.get_mut(key) {
+                Some(stats) => stats.add(entity_type, value, max_values),
+                None => {
+                    let mut stats = KeyStatistics::default();
+                    stats.add(entity_type, value, max_values);
+                    self.keys.insert(key.clone(), stats);
+                }
+            }
+        }
+    }
+}
+
+fn format_counts_csv(counts: &TypeCounts) -> String {
+    format!("{},{},{},{}", counts.total(), counts.nodes, counts.ways, counts.relations)
+}
+
+fn format_counts_json(counts: &TypeCounts) -> String {
+    format!("{{\"all\":{},\"nodes\":{},\"ways\":{},\"relations\":{}}}", counts.total(), counts.nodes, counts.ways, counts.relations)
+}
+
+fn json_string(value: &str) -> String {
+    serde_json::to_string(value).expect("strings always serialize")
+}
+
+/// Quotes a CSV field if it contains a separator, quote or line break.
+fn escape_csv(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+impl OsmVisitor for TagStatistics {
+    fn visit_node(&mut self, _id: i64, _latitude: f64, _longitude: f64, tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        self.add(OsmEntityType::Node, &tags);
+        Ok(Flow::Continue)
+    }
+
+    fn visit_way(&mut self, _id: i64, _nodes: Vec<NodeReference>, tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        self.add(OsmEntityType::Way, &tags);
+        Ok(Flow::Continue)
+    }
+
+    fn visit_relation(&mut self, _id: i64, _members: Vec<MemberReference>, tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        self.add(OsmEntityType::Relation, &tags);
+        Ok(Flow::Continue)
+    }
+}
@@ -1,60 +1,82 @@
 use ::PbfParseError;
 use blob::Blob;
-use osm::{MemberReference, NodeReference, EntityInfo};
+use osm::{BlockStrings, MemberReference, NodeReference, EntityInfo};
 use protos::osm::HeaderBlock;
 
+/// Returned from visit methods to decide whether traversal should continue. Stopping ends the traversal
+/// early without an error, still calling `end` on the visitor.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Flow {
+    Continue,
+    Stop,
+}
+
+pub type VisitResult = Result<Flow, PbfParseError>;
+
+/// Decides how traversal continues after an error is passed to `handle_error`.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ErrorPolicy {
+    /// Skip only the element that failed, continuing with the rest of its block. Errors that are not
+    /// specific to an element are treated as `SkipBlob`.
+    SkipElement,
+    /// Skip the rest of the blob the error occurred in
+    SkipBlob,
+    /// Stop the traversal, returning the error from `accept`
+    Abort,
+}
+
 pub trait BlobVisitor {
-    fn visit_blob(&mut self, blob: &Blob) -> Result<(), PbfParseError>;
+    fn visit_blob(&mut self, blob: &Blob) -> VisitResult;
 
     fn end(&mut self) -> Result<(), PbfParseError>;
 
-    fn handle_error(&mut self, _error: &PbfParseError) -> bool {
-        false
+    fn handle_error(&mut self, _error: &PbfParseError) -> ErrorPolicy {
+        ErrorPolicy::SkipBlob
     }
 }
 
 pub trait OsmVisitor {
-    fn visit_block(&mut self, _lat_offset: i64, _lon_offset: i64, _granularity: i32, _date_granularity: i32) -> Result<(), PbfParseError> {
-        Ok(())
+    fn visit_block(&mut self, _lat_offset: i64, _lon_offset: i64, _granularity: i32, _date_granularity: i32) -> VisitResult {
+        Ok(Flow::Continue)
     }
 
-    fn visit_string_table(&mut self, _strings: &Vec<&str>) -> Result<(), PbfParseError> {
-        Ok(())
+    fn visit_string_table(&mut self, _strings: &BlockStrings) -> VisitResult {
+        Ok(Flow::Continue)
     }
 
     fn end_block(&mut self) -> Result<(), PbfParseError> {
         Ok(())
     }
 
-    fn visit_group(&mut self) -> Result<(), PbfParseError> {
-        Ok(())
+    fn visit_group(&mut self) -> VisitResult {
+        Ok(Flow::Continue)
     }
 
     fn end_group(&mut self) -> Result<(), PbfParseError> {
         Ok(())
     }
 
-    fn visit_node(&mut self, _id: i64, _latitude: f64, _longitude: f64, _tags: Vec<(String, String)>, _info: EntityInfo) -> Result<(), PbfParseError> {
-        Ok(())
+    fn visit_node(&mut self, _id: i64, _latitude: f64, _longitude: f64, _tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        Ok(Flow::Continue)
     }
 
-    fn visit_way(&mut self, _id: i64, _nodes: Vec<NodeReference>, _tags: Vec<(String, String)>, _info: EntityInfo) -> Result<(), PbfParseError> {
-        Ok(())
+    fn visit_way(&mut self, _id: i64, _nodes: Vec<NodeReference>, _tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        Ok(Flow::Continue)
     }
 
-    fn visit_relation(&mut self, _id: i64, _members: Vec<MemberReference>, _tags: Vec<(String, String)>, _info: EntityInfo) -> Result<(), PbfParseError> {
-        Ok(())
+    fn visit_relation(&mut self, _id: i64, _members: Vec<MemberReference>, _tags: Vec<(String, String)>, _info: EntityInfo) -> VisitResult {
+        Ok(Flow::Continue)
     }
 
-    fn visit_header(&mut self, _block: &HeaderBlock) -> Result<(), PbfParseError> {
-        Ok(())
+    fn visit_header(&mut self, _block: &HeaderBlock) -> VisitResult {
+        Ok(Flow::Continue)
     }
 
     fn end(&mut self) -> Result<(), PbfParseError> {
         Ok(())
     }
 
-    fn handle_error(&mut self, _error: &PbfParseError) -> bool {
-        false
+    fn handle_error(&mut self, _error: &PbfParseError) -> ErrorPolicy {
+        ErrorPolicy::SkipBlob
     }
 }
@@ -6,10 +6,15 @@ use protos::osm::{DenseInfo, DenseNodes, HeaderBlock, Info, PrimitiveBlock, Prim
 use std::collections::HashMap;
 use std::i64;
 use std::io::Write;
+use std::mem;
 use std::ops;
-use visitor::OsmVisitor;
+use visitor::{Flow, OsmVisitor, VisitResult};
 
 const MAX_ENTITY_COUNT: usize = 8000;
+/// The precision of written coordinates in nanodegrees, matching the 7 decimal places of OSM coordinates
+const GRANULARITY: i64 = 100;
+/// The precision of written timestamps in milliseconds
+const DATE_GRANULARITY: i64 = 1000;
 
 pub struct PrimitiveBlockBuilder {
     nodes: Vec<NodeEntity>,
@@ -31,9 +36,7 @@ impl PrimitiveBlockBuilder {
     }
 
     fn append_node(&mut self, id: i64, latitude: f64, longitude: f64, tags: Vec<(String, String)>, info: EntityInfo) {
-        let lat_unit = (latitude / NANODEGREE_UNIT).floor() as i64;
-        let lon_unit = (longitude / NANODEGREE_UNIT).floor() as i64;
-        self.nodes.push(NodeEntity { id, latitude: lat_unit, longitude: lon_unit, tags, info });
+        self.nodes.push(NodeEntity { id, latitude: to_nanodegrees(latitude), longitude: to_nanodegrees(longitude), tags, info });
         self.complete_if_needed();
     }
 
@@ -72,7 +75,13 @@ impl PrimitiveBlockBuilder {
             strings.push_string(v);
         }
 
-        let pack_info = build_pack_info(&self.nodes);
+        for relation in relations.iter() {
+            for member in &relation.members {
+                strings.push_string(member.role.clone());
+            }
+        }
+
+        let pack_info = build_pack_info(&nodes);
 
         if !nodes.is_empty() {
             let mut node_group = PrimitiveGroup::default();
@@ -118,12 +127,16 @@ impl PrimitiveBlockBuilder {
     }
 
     fn take_blocks(&mut self) -> Vec<PrimitiveBlock> {
-        let blocks = self.completed_blocks.to_owned();
-        self.completed_blocks = Vec::new();
-        blocks
+        mem::replace(&mut self.completed_blocks, Vec::new())
     }
 }
 
+/// Converts degrees to nanodegrees, rounded to the granularity so that coordinates read from a file are written
+/// back unchanged.
+fn to_nanodegrees(degrees: f64) -> i64 {
+    (degrees / NANODEGREE_UNIT / GRANULARITY as f64).round() as i64 * GRANULARITY
+}
+
 fn build_pack_info(nodes: &Vec<NodeEntity>) -> PackInfo {
     if !nodes.is_empty() {
         let mut lat_offset = i64::MAX;
@@ -136,9 +149,9 @@ fn build_pack_info(nodes: &Vec<NodeEntity>) -> PackInfo {
                 lon_offset = node.longitude;
             }
         }
-        PackInfo { lat_offset, lon_offset, granularity: 100, date_granularity: 1000 }
+        PackInfo { lat_offset, lon_offset, granularity: GRANULARITY, date_granularity: DATE_GRANULARITY }
     } else {
-        PackInfo { lat_offset: 0, lon_offset: 0, granularity: 100, date_granularity: 1000 }
+        PackInfo { lat_offset: 0, lon_offset: 0, granularity: GRANULARITY, date_granularity: DATE_GRANULARITY }
     }
 }
 
@@ -156,8 +169,8 @@ fn build_dense_nodes(nodes: Vec<NodeEntity>, pack_info: &PackInfo, strings: &Rev
 
     for node in nodes.iter() {
         let local_id = node.id;
-        let local_lat = (node.latitude / pack_info.granularity) - pack_info.lat_offset;
-        let local_lon = (node.longitude / pack_info.granularity) - pack_info.lon_offset;
+        let local_lat = (node.latitude - pack_info.lat_offset) / pack_info.granularity;
+        let local_lon = (node.longitude - pack_info.lon_offset) / pack_info.granularity;
 
         id.push(local_id - prev_id);
         lat.push(local_lat - prev_lat);
@@ -165,8 +178,8 @@ fn build_dense_nodes(nodes: Vec<NodeEntity>, pack_info: &PackInfo, strings: &Rev
 
         if has_tags {
             for (k, v) in node.tags.iter() {
-                tags.push(strings.lookup_string(&k).unwrap());
-                tags.push(strings.lookup_string(&v).unwrap());
+                tags.push(strings.lookup_string(&k).unwrap() as i32);
+                tags.push(strings.lookup_string(&v).unwrap() as i32);
             }
             tags.push(0);
         }
@@ -180,8 +193,7 @@ fn build_dense_nodes(nodes: Vec<NodeEntity>, pack_info: &PackInfo, strings: &Rev
     dense_nodes.set_id(id);
     dense_nodes.set_lat(lat);
     dense_nodes.set_lon(lon);
-
-    // TODO: Tags
+    dense_nodes.set_keys_vals(tags);
     dense_nodes.set_denseinfo(build_dense_info(nodes, pack_info, metadata));
 
     dense_nodes
@@ -288,7 +300,7 @@ fn build_relations(relations: Vec<RelationEntity>, pack_info: &PackInfo, strings
                 let id = member.id;
 
                 member_ids.push(id - prev_id);
-                roles.push(member.role_sid);
+                roles.push(strings.lookup_string(&member.role).unwrap() as i32);
                 types.push(member.entity_type.into());
 
                 prev_id = id;
@@ -321,6 +333,7 @@ fn build_info(info: EntityInfo, pack_info: &PackInfo, metadata: bool) -> Info {
 pub struct OsmWriterVisitor<'a> {
     writer: &'a mut Write,
     builder: PrimitiveBlockBuilder,
+    header_written: bool,
 }
 
 impl<'a> OsmWriterVisitor<'a> {
@@ -328,9 +341,12 @@ impl<'a> OsmWriterVisitor<'a> {
         OsmWriterVisitor {
             writer,
             builder: PrimitiveBlockBuilder::new(write_metadata),
+            header_written: false,
         }
     }
 
+    /// Writes out any blocks the builder has filled. This is done after every entity rather than only at the end of
+    /// each input block, since entities pulled from several files by a merge don't arrive in blocks.
     fn write_completed(&mut self) -> Result<(), PbfParseError> {
         use protobuf::Message;
         let completed = self.builder.take_blocks();
@@ -349,29 +365,39 @@ impl<'a> OsmVisitor for OsmWriterVisitor<'a> {
         Ok(())
     }
 
-    fn visit_node(&mut self, id: i64, latitude: f64, longitude: f64, tags: Vec<(String, String)>, info: EntityInfo) -> Result<(), PbfParseError> {
+    fn visit_node(&mut self, id: i64, latitude: f64, longitude: f64, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
         self.builder.append_node(id, latitude, longitude, tags, info);
-        Ok(())
+        self.write_completed()?;
+        Ok(Flow::Continue)
     }
 
-    fn visit_way(&mut self, id: i64, nodes: Vec<NodeReference>, tags: Vec<(String, String)>, info: EntityInfo) -> Result<(), PbfParseError> {
+    fn visit_way(&mut self, id: i64, nodes: Vec<NodeReference>, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
         self.builder.append_way(id, nodes, tags, info);
-        Ok(())
+        self.write_completed()?;
+        Ok(Flow::Continue)
     }
 
-    fn visit_relation(&mut self, id: i64, members: Vec<MemberReference>, tags: Vec<(String, String)>, info: EntityInfo) -> Result<(), PbfParseError> {
+    fn visit_relation(&mut self, id: i64, members: Vec<MemberReference>, tags: Vec<(String, String)>, info: EntityInfo) -> VisitResult {
         self.builder.append_relation(id, members, tags, info);
-        Ok(())
+        self.write_completed()?;
+        Ok(Flow::Continue)
     }
 
-    fn visit_header(&mut self, block: &HeaderBlock) -> Result<(), PbfParseError> {
+    /// Writes the header block. A file only has one, so the headers of any further inputs written through the
+    /// same writer are dropped.
+    fn visit_header(&mut self, block: &HeaderBlock) -> VisitResult {
         use protobuf::Message;
 
+        if self.header_written {
+            return Ok(Flow::Continue);
+        }
+        self.header_written = true;
+
         let bytes = block.write_to_bytes()?;
         let blob = Blob::new(BlobType::HEADER, bytes);
         blob.write(self.writer)?;
 
-        Ok(())
+        Ok(Flow::Continue)
     }
 
     fn end(&mut self) -> Result<(), PbfParseError> {
@@ -417,10 +443,13 @@ struct ReverseStringTable {
 
 impl ReverseStringTable {
     fn new() -> ReverseStringTable {
-        ReverseStringTable {
+        let mut table = ReverseStringTable {
             strings: Vec::new(),
             reverse_strings: HashMap::new(),
-        }
+        };
+        // Index 0 is reserved, since it delimits the tags of dense nodes
+        table.push_string(String::new());
+        table
     }
 
     fn push_string(&mut self, string: String) {